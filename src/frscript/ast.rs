@@ -16,6 +16,11 @@ pub enum FRType {
     BoolT,
     Any,
     Unit,
+    TypeVar(uint),
+    // a type variable that may only stand for one of the given types
+    OneOf(uint, ~[FRType]),
+    // applies the quotation on top of the stack
+    Apply,
 }
 
 impl ToStr for FRType {
@@ -26,13 +31,16 @@ impl ToStr for FRType {
             HasField(name, T) => name + ": " + T.to_str(),
             Union(a) => a.map(|v| v.to_str()).connect(" + "),
             Func(a) => a.map(|v| v.to_str()).connect(" -> "),
-            ExprT {tin: _, tout: tout} => tout.to_str(),
+            ExprT {tin: tin, tout: tout} => format!("({:s} -- {:s})", tin.map(|v| v.to_str()).connect(" "), tout.map(|v| v.to_str()).connect(" ")),
             StringT => ~"str",
             Integer => ~"int",
             Float => ~"float",
             BoolT => ~"bool",
             Any => ~"any",
             Unit => ~"()",
+            TypeVar(n) => format!("'{:c}", "abcdefghijklmnopqrstuvwxyz".char_at(n % 26)),
+            OneOf(n, a) => format!("'{:c}({:s})", "abcdefghijklmnopqrstuvwxyz".char_at(n % 26), a.map(|v| v.to_str()).connect(" + ")),
+            Apply => ~"call",
         }
     }
 }
@@ -46,8 +54,12 @@ impl FRTypeOf for FRValue {
         match self.clone() {
             String(_)       => @StringT,
             Number(_)       => @Float,
+            Int(_)          => @Integer,
             List(_)         => @ListT,
             Function(_,_)   => @Unit,
+            Word(_)         => @Unit,
+            Quotation(q)    => q.typeinfo,
+            Compiled(_, T)  => T,
             Bool(_)         => @BoolT,
             Nil             => @Unit,
         }
@@ -68,6 +80,13 @@ impl Eq for FRType {
             (BoolT, BoolT)                      => true,
             (Any, _)                            => true,
             (Unit, Unit)                        => true,
+            (TypeVar(x), TypeVar(y))            => x == y,
+            (OneOf(x, _), OneOf(y, _))          => x == y,
+            (ExprT {tin: a, tout: b}, ExprT {tin: c, tout: d})
+                                                => a.len() == c.len() && b.len() == d.len() &&
+                                                   a.iter().zip(c.iter()).all(|(u, v)| u == v) &&
+                                                   b.iter().zip(d.iter()).all(|(u, v)| u == v),
+            (Apply, Apply)                      => true,
             _                                   => false
         }
     }
@@ -87,6 +106,13 @@ impl FRType {
             (BoolT, BoolT)                      => true,
             (Any, _)                            => true,
             (Unit, Unit)                        => true,
+            (TypeVar(x), TypeVar(y))            => x == y,
+            (OneOf(x, _), OneOf(y, _))          => x == y,
+            (ExprT {tin: a, tout: b}, ExprT {tin: c, tout: d})
+                                                => a.len() == c.len() && b.len() == d.len() &&
+                                                   a.iter().zip(c.iter()).all(|(u, v)| u == v) &&
+                                                   b.iter().zip(d.iter()).all(|(u, v)| u == v),
+            (Apply, Apply)                      => true,
             _                                   => false
         }
     }
@@ -96,8 +122,13 @@ impl FRType {
 pub enum FRValue {
     String(~str),
     Number(f32),
+    Int(i64),
     List(~[FRValue]),
-    Function(~extern fn(&mut context::Context,~[FRValue]) -> Result<~[FRValue], ~str>, uint),
+    Function(~extern fn(&mut context::Context,&[FRValue]) -> Result<~[FRValue], ~str>, uint),
+    Word(~AST),
+    Quotation(~AST),
+    // a quotation compiled into a program, by address
+    Compiled(uint, @FRType),
     Bool(bool),
     Nil
 }
@@ -107,8 +138,12 @@ impl ToStr for FRValue {
         match self.clone() {
             String(s)       => format!("\"{:s}\"", s),
             Number(n)       => format!("{:f}", n),
+            Int(n)          => n.to_str(),
             List(l)         => "(" + l.map(|x| x.to_str()).connect(" ") + ")",
             Function(_,_)   => ~"function",
+            Word(_)         => ~"word",
+            Quotation(q)    => format!("[{:s}]", q.typeinfo.to_str()),
+            Compiled(_, T)  => format!("[{:s}]", T.to_str()),
             Bool(b)         => if b { ~"true" } else { ~"false" },
             Nil             => ~"()",
         }
@@ -119,7 +154,10 @@ impl ToStr for FRValue {
 pub enum ASTNode {
     Expr(~[AST]),
     Var(~str),
-    Literal(FRValue)
+    Literal(FRValue),
+    Def(~str, Option<@FRType>, ~AST),
+    If(~AST, ~AST, Option<~AST>),
+    Call
 }
 
 #[deriving(Clone)]
@@ -140,6 +178,31 @@ impl ToStr for ParseError {
     }
 }
 
+fn build_type(scope: &context::Scope, tok: &Token<grammar::FRToken>) -> Result<FRType, ParseError> {
+    match tok.value {
+        grammar::Label(ref name) => match scope.lookup_type(name.clone()) {
+            Some(T) => Ok((*T).clone()),
+            None => Err(ParseError {msg: format!("No such type {:s}", *name), line: tok.line})
+        },
+        grammar::Effect(ref tin, ref tout) => match (build_types(scope, *tin), build_types(scope, *tout)) {
+            (Ok(tin), Ok(tout)) => Ok(ExprT {tin: tin, tout: tout}),
+            (Err(e), _) | (_, Err(e)) => Err(e)
+        },
+        _ => Err(ParseError {msg: format!("Unexpected token: {:?}", tok.value), line: tok.line})
+    }
+}
+
+fn build_types(scope: &context::Scope, toks: &[Token<grammar::FRToken>]) -> Result<~[FRType], ParseError> {
+    let mut res = ~[];
+    for t in toks.iter() {
+        match build_type(scope, t) {
+            Ok(T) => res.push(T),
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(res)
+}
+
 pub fn build_ast(scope: &mut context::Scope, tok: Token<grammar::FRToken>) -> Result<AST, ParseError> {
     let build_var = |name| Ok(AST {node: Var(name), line: tok.line, typeinfo: @Unit});
     let build_literal = |val: FRValue| Ok(AST {node: Literal(val.clone()), line: tok.line, typeinfo: val.FRtype_of()});
@@ -148,9 +211,11 @@ pub fn build_ast(scope: &mut context::Scope, tok: Token<grammar::FRToken>) -> Re
         grammar::Unparsed(_t) => Err(ParseError {msg: format!("Unexpected token: {:?}", tok.value), line: tok.line}),
         grammar::Whitespace => Err(ParseError {msg: ~"Unexpected whitespace token", line: tok.line}),
         grammar::FRSeq(_a) => Err(ParseError {msg: format!("Unexpected token: {:?}", tok.value), line: tok.line}),
+        grammar::Effect(_, _) => Err(ParseError {msg: format!("Unexpected token: {:?}", tok.value), line: tok.line}),
         grammar::Label(s) => build_var(s),
         grammar::String(s) => build_literal(String(s)),
         grammar::Number(v) => build_literal(Number(v)),
+        grammar::Int(v) => build_literal(Int(v)),
         grammar::Bool(b) => build_literal(Bool(b)),
         grammar::Expr(ref arr) => {
             let mut res = ~[];
@@ -162,6 +227,47 @@ pub fn build_ast(scope: &mut context::Scope, tok: Token<grammar::FRToken>) -> Re
             }
             build_expr(res)
         }
+        grammar::Quote(ref arr) => {
+            let mut res = ~[];
+            for t in arr.iter() {
+                match build_ast(scope, t.clone()) {
+                    Ok(v) => res.push(v),
+                    Err(e) => return Err(e)
+                }
+            }
+            build_literal(Quotation(~AST {node: Expr(res), line: tok.line, typeinfo: @Unit}))
+        }
+        grammar::Def(name, effect, body) => {
+            let effect = match effect {
+                Some(t) => match build_type(scope, t) {
+                    Ok(T) => Some(@T),
+                    Err(e) => return Err(e)
+                },
+                None => None
+            };
+            match build_ast(scope, *body) {
+                Ok(v) => Ok(AST {node: Def(name, effect, ~v), line: tok.line, typeinfo: @Unit}),
+                Err(e) => Err(e)
+            }
+        },
+        grammar::If(cond, body, other) => {
+            let cond = match build_ast(scope, *cond) {
+                Ok(v) => v,
+                Err(e) => return Err(e)
+            };
+            let body = match build_ast(scope, *body) {
+                Ok(v) => v,
+                Err(e) => return Err(e)
+            };
+            let other = match other {
+                Some(t) => match build_ast(scope, *t) {
+                    Ok(v) => Some(~v),
+                    Err(e) => return Err(e)
+                },
+                None => None
+            };
+            Ok(AST {node: If(~cond, ~body, other), line: tok.line, typeinfo: @Unit})
+        }
     }
 }
 
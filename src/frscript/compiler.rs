@@ -0,0 +1,240 @@
+use std::hashmap::*;
+use parse::*;
+use context::*;
+use ast::*;
+
+pub struct CompileError {
+    msg: ~str,
+    line: LineInfo
+}
+
+impl ToStr for CompileError {
+    fn to_str(&self) -> ~str {
+        self.line.to_str() + ": " + self.msg.clone()
+    }
+}
+
+#[deriving(Clone)]
+pub enum Instr {
+    // push a value from the constant table
+    Push(uint),
+    // call a host function from the host table
+    CallHost(uint),
+    // call the word whose address is in the word table
+    CallWord(uint),
+    // pop a quotation and call it
+    CallQuote,
+    Jump(uint),
+    // pop a bool and jump if it's false
+    JumpUnless(uint),
+    Return
+}
+
+pub struct Program {
+    code: ~[Instr],
+    lines: ~[LineInfo],
+    consts: ~[FRValue],
+    hosts: ~[(extern fn(&mut Context,&[FRValue]) -> Result<~[FRValue], ~str>, uint)],
+    words: ~[uint],
+    hostslots: HashMap<~str, uint>,
+    wordslots: HashMap<~str, uint>,
+}
+
+// How big a program was, so that what gets added to it after can be dropped.
+pub struct Mark {
+    code: uint,
+    consts: uint,
+    words: uint
+}
+
+impl Program {
+    pub fn new() -> Program {
+        Program {code: ~[], lines: ~[], consts: ~[], hosts: ~[], words: ~[], hostslots: HashMap::new(), wordslots: HashMap::new()}
+    }
+    fn constant(&mut self, val: FRValue) -> uint {
+        self.consts.push(val);
+        self.consts.len() - 1
+    }
+    fn host(&mut self, name: ~str, f: extern fn(&mut Context,&[FRValue]) -> Result<~[FRValue], ~str>, nargs: uint) -> uint {
+        match self.hostslots.find(&name) {
+            Some(&slot) => return slot,
+            None => ()
+        }
+        self.hosts.push((f, nargs));
+        self.hostslots.insert(name, self.hosts.len() - 1);
+        self.hosts.len() - 1
+    }
+    fn word(&mut self, name: ~str) -> uint {
+        match self.wordslots.find(&name) {
+            Some(&slot) => return slot,
+            None => ()
+        }
+        self.define(name)
+    }
+    // Gives a new definition of a word its own slot, so that code already
+    // compiled against an older one goes on calling that.
+    fn define(&mut self, name: ~str) -> uint {
+        self.words.push(0);
+        self.wordslots.insert(name, self.words.len() - 1);
+        self.words.len() - 1
+    }
+    pub fn mark(&self) -> Mark {
+        Mark {code: self.code.len(), consts: self.consts.len(), words: self.words.len()}
+    }
+    // Drops the code and constants added since mark, once a toplevel
+    // statement compiled after it has run, unless a word was compiled since
+    // or something left on the stack still refers to the code.
+    pub fn release(&mut self, mark: Mark, stack: &[FRValue]) {
+        if self.words.len() > mark.words || stack.iter().any(|v| refers(v, mark.code)) {
+            return
+        }
+        self.code.truncate(mark.code);
+        self.lines.truncate(mark.code);
+        self.consts.truncate(mark.consts);
+    }
+    // Appends a chunk to the program, returning its address.
+    fn link(&mut self, chunk: Chunk) -> uint {
+        let base = self.code.len();
+        for (instr, line) in chunk.code.iter().zip(chunk.lines.iter()) {
+            self.code.push(match *instr {
+                Jump(addr) => Jump(addr + base),
+                JumpUnless(addr) => JumpUnless(addr + base),
+                x => x
+            });
+            self.lines.push(*line);
+        }
+        base
+    }
+}
+
+// Code for one word, quotation or toplevel statement, with jumps relative to
+// the start of the chunk.
+struct Chunk {
+    code: ~[Instr],
+    lines: ~[LineInfo]
+}
+
+impl Chunk {
+    fn new() -> Chunk {
+        Chunk {code: ~[], lines: ~[]}
+    }
+    fn emit(&mut self, instr: Instr, line: LineInfo) -> uint {
+        self.code.push(instr);
+        self.lines.push(line);
+        self.code.len() - 1
+    }
+}
+
+// Whether a value holds a quotation compiled at or after addr.
+fn refers(v: &FRValue, addr: uint) -> bool {
+    match *v {
+        Compiled(a, _) => a >= addr,
+        List(ref arr) => arr.iter().any(|x| refers(x, addr)),
+        _ => false
+    }
+}
+
+// The slot is claimed before compiling the body so that it can call itself.
+fn compile_word(ctx: &Context, prog: &mut Program, slot: uint, body: &AST) -> Result<uint, CompileError> {
+    match compile(ctx, prog, body) {
+        Ok(addr) => {
+            prog.words[slot] = addr;
+            Ok(slot)
+        }
+        Err(e) => Err(e)
+    }
+}
+
+fn compile_node(ctx: &Context, prog: &mut Program, chunk: &mut Chunk, ast: &AST) -> Result<(), CompileError> {
+    match ast.node {
+        Expr(ref arr) => {
+            for a in arr.iter() {
+                match compile_node(ctx, prog, chunk, a) {
+                    Ok(()) => (),
+                    Err(e) => return Err(e)
+                }
+            }
+        }
+        Var(ref name) => match ctx.lookup(name.clone()) {
+            Some((Function(f, nargs), _)) => {
+                let slot = prog.host(name.clone(), *f, nargs);
+                chunk.emit(CallHost(slot), ast.line);
+            }
+            Some((Word(body), _)) => {
+                let slot = match prog.wordslots.find(name) {
+                    Some(&slot) => slot,
+                    None => {
+                        let slot = prog.word(name.clone());
+                        match compile_word(ctx, prog, slot, body) {
+                            Ok(slot) => slot,
+                            Err(e) => return Err(e)
+                        }
+                    }
+                };
+                chunk.emit(CallWord(slot), ast.line);
+            }
+            Some((val, _)) => {
+                let idx = prog.constant(val);
+                chunk.emit(Push(idx), ast.line);
+            }
+            None => return Err(CompileError {msg: ~"ICE: Type checker didn't catch non-existent value", line: ast.line})
+        },
+        Literal(Quotation(ref body)) => match compile(ctx, prog, *body) {
+            Ok(addr) => {
+                let idx = prog.constant(Compiled(addr, body.typeinfo));
+                chunk.emit(Push(idx), ast.line);
+            }
+            Err(e) => return Err(e)
+        },
+        Literal(ref val) => {
+            let idx = prog.constant(val.clone());
+            chunk.emit(Push(idx), ast.line);
+        }
+        If(ref cond, ref body, ref other) => {
+            match compile_node(ctx, prog, chunk, *cond) {
+                Ok(()) => (),
+                Err(e) => return Err(e)
+            }
+            let branch = chunk.emit(JumpUnless(0), ast.line);
+            match compile_node(ctx, prog, chunk, *body) {
+                Ok(()) => (),
+                Err(e) => return Err(e)
+            }
+            match *other {
+                Some(ref e) => {
+                    let skip = chunk.emit(Jump(0), ast.line);
+                    chunk.code[branch] = JumpUnless(chunk.code.len());
+                    match compile_node(ctx, prog, chunk, *e) {
+                        Ok(()) => (),
+                        Err(e) => return Err(e)
+                    }
+                    chunk.code[skip] = Jump(chunk.code.len());
+                }
+                None => chunk.code[branch] = JumpUnless(chunk.code.len())
+            }
+        }
+        Call => {
+            chunk.emit(CallQuote, ast.line);
+        }
+        Def(ref name, _, ref body) => {
+            let slot = prog.define(name.clone());
+            match compile_word(ctx, prog, slot, *body) {
+                Ok(_) => (),
+                Err(e) => return Err(e)
+            }
+        }
+    }
+    Ok(())
+}
+
+// Compiles a typechecked AST into the program, returning the address to run it from.
+pub fn compile(ctx: &Context, prog: &mut Program, ast: &AST) -> Result<uint, CompileError> {
+    let mut chunk = Chunk::new();
+    match compile_node(ctx, prog, &mut chunk, ast) {
+        Ok(()) => {
+            chunk.emit(Return, ast.line);
+            Ok(prog.link(chunk))
+        }
+        Err(e) => Err(e)
+    }
+}
@@ -17,9 +17,18 @@ impl Scope {
     pub fn lookup_macro(&self, name: ~str) -> Option<~extern fn(~[AST]) -> AST> {
         self.macros.find(&name).and_then(|x| Some(x.clone()))
     }
+    pub fn lookup_type(&self, name: ~str) -> Option<@FRType> {
+        self.types.find(&name).and_then(|x| Some(x.clone()))
+    }
     pub fn define(&mut self, name: ~str, val: FRValue, T: @FRType) {
         self.atoms.insert(name, (val, T));
     }
+    pub fn undefine(&mut self, name: ~str) {
+        self.atoms.remove(&name);
+    }
+    pub fn define_type(&mut self, name: ~str, T: @FRType) {
+        self.types.insert(name, T);
+    }
     pub fn macro(&mut self, name: ~str, f: ~extern fn(~[AST]) -> AST) {
         self.macros.insert(name, f);
     }
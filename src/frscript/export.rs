@@ -0,0 +1,325 @@
+// Writes grammars out for documentation, as ISO EBNF text or as SVG railroad
+// diagrams with one picture per rule.
+
+use std::hashmap::*;
+use std::str::*;
+use std::vec;
+use std::uint;
+use parse::*;
+
+// The order rules are written in: the start rules and then the rules they
+// use as they're found, followed by anything unreachable.
+fn rule_order<'a, T>(ctx: &ParseContext<'a, T>, roots: &[&'a str]) -> ~[&'a str] {
+    let mut seen = HashSet::new();
+    let mut order = ~[];
+    let mut i = 0;
+    let mut todo = roots.to_owned();
+    while i < todo.len() {
+        let name = todo[i];
+        i += 1;
+        if seen.contains(&name) || !ctx.grammar.contains_key(&name) {
+            continue
+        }
+        seen.insert(name);
+        order.push(name);
+        each_rule(ctx.grammar.get(&name), |r| todo.push(r));
+    }
+    for (name, _) in ctx.grammar.iter() {
+        if !seen.contains(name) {
+            order.push(*name);
+        }
+    }
+    order
+}
+
+// Terminals can't hold control characters, nor both kinds of quote, so
+// those are written as special sequences instead.
+fn terminal(s: &str) -> ~str {
+    if s.iter().any(|c| (c as uint) < 32) || (s.contains_char('"') && s.contains_char('\'')) {
+        format!("? \"{:s}\" ?", s.escape_default())
+    } else if s.contains_char('"') {
+        format!("'{:s}'", s)
+    } else {
+        format!("\"{:s}\"", s)
+    }
+}
+
+// Precedence goes from alternation (0) through sequence (1) and exception (2)
+// to single factors (3); anything looser than the context is parenthesised.
+fn ebnf<'a, T:Clone>(pat: &Pattern<'a, T>, prec: uint) -> ~str {
+    let wrap: &fn(~str, uint) -> ~str = |s, level| if prec > level { "( " + s + " )" } else { s };
+    match *pat {
+        Rule(name) => name.to_owned(),
+        Literal(s) => terminal(s),
+        Range(x, y) => format!("? characters {:s} to {:s} ?", terminal(from_char(x)), terminal(from_char(y))),
+        Chars(1) => ~"? any character ?",
+        Chars(n) => wrap(format!("{:u} * ? any character ?", n), 2),
+        Set(ref arr) if arr.len() == 1 => terminal(from_char(arr[0])),
+        Set(ref arr) => wrap(arr.map(|c| terminal(from_char(*c))).connect(" | "), 0),
+        Class(c) => format!("? any {:s} ?", c.to_str()),
+        More(ref p) | MoreThan(0, ref p) => "{ " + ebnf(*p, 0) + " }",
+        MoreThan(1, ref p) => wrap(ebnf(*p, 2) + " , { " + ebnf(*p, 0) + " }", 1),
+        MoreThan(n, ref p) => wrap(format!("{:u} * {:s} , ", n, ebnf(*p, 3)) + "{ " + ebnf(*p, 0) + " }", 1),
+        Exactly(n, ref p) => wrap(format!("{:u} * {:s}", n, ebnf(*p, 3)), 2),
+        LessThan(1, ref p) => "[ " + ebnf(*p, 0) + " ]",
+        LessThan(n, ref p) => wrap(format!("{:u} * [ {:s} ]", n, ebnf(*p, 0)), 2),
+        Seq(ref arr) => wrap(arr.map(|p| ebnf(p, 2)).connect(" , "), 1),
+        Or(ref arr) if arr.len() == 0 => ~"? nothing ?",
+        Or(ref arr) => wrap(arr.map(|p| ebnf(p, 1)).connect(" | "), 0),
+        Diff(ref a, ref b) => wrap(ebnf(*a, 3) + " - " + ebnf(*b, 3), 2),
+        Precedence(ref p, ref ops) if ops.len() == 0 => ebnf(*p, prec),
+        Precedence(ref p, ref ops) => wrap(ebnf(*p, 2) + " , { ( " + ops.map(|x| ebnf(&x.pat, 1)).connect(" | ") + " ) , " + ebnf(*p, 2) + " }", 1),
+        And(ref p) => "? followed by " + ebnf(*p, 0) + " ?",
+        Not(ref p) => "? not followed by " + ebnf(*p, 0) + " ?",
+        Eof => ~"? end of input ?",
+        Predicate(ref p, _) => wrap(ebnf(*p, 3) + " , ? checked by a predicate ?", 1),
+        Capture(name, ref p) => wrap(ebnf(*p, 3) + " , ? called " + name + " ?", 1),
+        Backref(name) => "? the text matched by " + name + " ?",
+        Always(_) => ~"? nothing ?",
+        Match(_) => ~"? custom matcher ?",
+        Recover(ref p, _) | Build(ref p, _) | Map(ref p, _) => ebnf(*p, prec)
+    }
+}
+
+// Every rule in the grammar as EBNF, one per line.
+pub fn to_ebnf<'a, T:Clone>(ctx: &ParseContext<'a, T>, roots: &[&'a str]) -> ~str {
+    let mut out = ~"";
+    for name in rule_order(ctx, roots).iter() {
+        out.push_str(format!("{:s} = {:s} ;\n", *name, ebnf(ctx.grammar.get(name), 0)));
+    }
+    out
+}
+
+#[deriving(Clone)]
+enum Diagram {
+    Terminal(~str),
+    NonTerminal(~str),
+    // a note on the line, for things the diagram can't draw
+    Comment(~str),
+    Skip,
+    Sequence(~[Diagram]),
+    Choice(~[Diagram]),
+    // one or more times
+    Loop(~Diagram)
+}
+
+fn sequence(arr: ~[Diagram]) -> Diagram {
+    let mut arr = arr;
+    if arr.len() == 1 { arr.pop() } else { Sequence(arr) }
+}
+
+// A choice of nothing never matches, which is noted instead.
+fn choice(arr: ~[Diagram]) -> Diagram {
+    if arr.len() == 0 { Comment(~"nothing") } else { Choice(arr) }
+}
+
+fn diagram<'a, T:Clone>(pat: &Pattern<'a, T>) -> Diagram {
+    let visible = |c: char| from_char(c).escape_default();
+    match *pat {
+        Rule(name) => NonTerminal(name.to_owned()),
+        Literal(s) => Terminal(s.escape_default()),
+        Range(x, y) => Terminal(format!("[{:s}-{:s}]", visible(x), visible(y))),
+        Chars(n) => sequence(vec::from_elem(n, Terminal(~"any"))),
+        Set(ref arr) if arr.len() == 1 => Terminal(visible(arr[0])),
+        Set(ref arr) => Terminal("[" + arr.map(|c| visible(*c)).concat() + "]"),
+        Class(c) => NonTerminal(c.to_str()),
+        More(ref p) | MoreThan(0, ref p) => Choice(~[Skip, Loop(~diagram(*p))]),
+        MoreThan(n, ref p) => {
+            let mut arr = vec::from_elem(n - 1, diagram(*p));
+            arr.push(Loop(~diagram(*p)));
+            sequence(arr)
+        }
+        Exactly(n, ref p) => sequence(vec::from_elem(n, diagram(*p))),
+        LessThan(n, ref p) => sequence(vec::from_elem(n, Choice(~[Skip, diagram(*p)]))),
+        Seq(ref arr) => sequence(arr.map(|p| diagram(p))),
+        Or(ref arr) => choice(arr.map(|p| diagram(p))),
+        Diff(ref a, ref b) => Sequence(~[diagram(*a), Comment("except " + ebnf(*b, 0))]),
+        Precedence(ref p, ref ops) if ops.len() == 0 => diagram(*p),
+        Precedence(ref p, ref ops) => {
+            let rest = Sequence(~[Choice(ops.map(|x| diagram(&x.pat))), diagram(*p)]);
+            Sequence(~[diagram(*p), Choice(~[Skip, Loop(~rest)])])
+        }
+        And(ref p) => Comment("followed by " + ebnf(*p, 0)),
+        Not(ref p) => Comment("not followed by " + ebnf(*p, 0)),
+        Eof => Comment(~"end of input"),
+        Predicate(ref p, _) => Sequence(~[diagram(*p), Comment(~"if accepted")]),
+        Capture(name, ref p) => Sequence(~[diagram(*p), Comment("called " + name)]),
+        Backref(name) => Comment("same text as " + name),
+        Always(_) => Skip,
+        Match(_) => Comment(~"custom matcher"),
+        Recover(ref p, _) | Build(ref p, _) | Map(ref p, _) => diagram(*p)
+    }
+}
+
+static CHAR: uint = 8;
+static GAP: uint = 10;
+
+// The width of a diagram and how far it reaches above and below its line.
+fn measure(d: &Diagram) -> (uint, uint, uint) {
+    match *d {
+        Terminal(ref s) | NonTerminal(ref s) => (s.char_len() * CHAR + 20, 11, 11),
+        Comment(ref s) => (s.char_len() * CHAR + 10, 16, 0),
+        Skip => (0, 0, 0),
+        Sequence(ref arr) => {
+            let (mut w, mut up, mut down) = (0, 0, 0);
+            for (i, p) in arr.iter().enumerate() {
+                let (pw, pu, pd) = measure(p);
+                w += if i > 0 { GAP + pw } else { pw };
+                up = uint::max(up, pu);
+                down = uint::max(down, pd);
+            }
+            (w, up, down)
+        }
+        Choice(ref arr) => {
+            let (mut w, mut up, mut down) = (0, 0, 0);
+            for (i, p) in arr.iter().enumerate() {
+                let (pw, pu, pd) = measure(p);
+                w = uint::max(w, pw);
+                if i == 0 {
+                    up = pu;
+                    down = pd;
+                } else {
+                    down += uint::max(GAP + pu, 20) + pd;
+                }
+            }
+            (w + 40, up, down)
+        }
+        Loop(ref p) => {
+            let (w, up, down) = measure(*p);
+            (w + 40, up, uint::max(down + GAP, 20))
+        }
+    }
+}
+
+fn escape(s: &str) -> ~str {
+    s.replace("&", "&amp;").replace("<", "&lt;").replace(">", "&gt;").replace("\"", "&quot;")
+}
+
+fn line(out: &mut ~str, x1: uint, y1: uint, x2: uint, y2: uint) {
+    out.push_str(format!("<line x1=\"{:u}\" y1=\"{:u}\" x2=\"{:u}\" y2=\"{:u}\"/>\n", x1, y1, x2, y2));
+}
+
+// Draws the diagram with its line entering at (x, y).
+fn draw(d: &Diagram, x: uint, y: uint, out: &mut ~str) {
+    let (w, _, _) = measure(d);
+    match *d {
+        Terminal(ref s) | NonTerminal(ref s) => {
+            let rx = match *d {
+                Terminal(_) => 10,
+                _ => 0
+            };
+            out.push_str(format!("<rect x=\"{:u}\" y=\"{:u}\" width=\"{:u}\" height=\"22\" rx=\"{:u}\"/>\n", x, y - 11, w, rx));
+            out.push_str(format!("<text x=\"{:u}\" y=\"{:u}\">{:s}</text>\n", x + w / 2, y + 4, escape(*s)));
+        }
+        Comment(ref s) => {
+            line(out, x, y, x + w, y);
+            out.push_str(format!("<text class=\"comment\" x=\"{:u}\" y=\"{:u}\">{:s}</text>\n", x + w / 2, y - 5, escape(*s)));
+        }
+        Skip => (),
+        Sequence(ref arr) => {
+            let mut cx = x;
+            for (i, p) in arr.iter().enumerate() {
+                if i > 0 {
+                    line(out, cx, y, cx + GAP, y);
+                    cx += GAP;
+                }
+                draw(p, cx, y, out);
+                let (pw, _, _) = measure(p);
+                cx += pw;
+            }
+        }
+        Choice(ref arr) => {
+            let mut cy = y;
+            let mut below = 0;
+            for (i, p) in arr.iter().enumerate() {
+                let (pw, pu, pd) = measure(p);
+                if i == 0 {
+                    line(out, x, y, x + 20, y);
+                    line(out, x + w - 20, y, x + w, y);
+                } else {
+                    cy += below + uint::max(GAP + pu, 20);
+                    out.push_str(format!("<path d=\"M{:u} {:u} Q{:u} {:u} {:u} {:u} V{:u} Q{:u} {:u} {:u} {:u}\"/>\n",
+                                         x, y, x + 10, y, x + 10, y + 10, cy - 10, x + 10, cy, x + 20, cy));
+                    out.push_str(format!("<path d=\"M{:u} {:u} Q{:u} {:u} {:u} {:u} V{:u} Q{:u} {:u} {:u} {:u}\"/>\n",
+                                         x + w - 20, cy, x + w - 10, cy, x + w - 10, cy - 10, y + 10, x + w - 10, y, x + w, y));
+                }
+                draw(p, x + 20, cy, out);
+                line(out, x + 20 + pw, cy, x + w - 20, cy);
+                below = pd;
+            }
+        }
+        Loop(ref p) => {
+            let (pw, _, _) = measure(*p);
+            let (_, _, down) = measure(d);
+            let by = y + down;
+            line(out, x, y, x + 20, y);
+            draw(*p, x + 20, y, out);
+            line(out, x + 20 + pw, y, x + w, y);
+            out.push_str(format!("<path d=\"M{:u} {:u} Q{:u} {:u} {:u} {:u} V{:u} Q{:u} {:u} {:u} {:u} H{:u} Q{:u} {:u} {:u} {:u} V{:u} Q{:u} {:u} {:u} {:u}\"/>\n",
+                                 x + w - 20, y, x + w - 10, y, x + w - 10, y + 10, by - 10, x + w - 10, by, x + w - 20, by,
+                                 x + 20, x + 10, by, x + 10, by - 10, y + 10, x + 10, y, x + 20, y));
+        }
+    }
+}
+
+// A standalone SVG railroad diagram for one rule.
+pub fn to_railroad<'a, T:Clone>(ctx: &ParseContext<'a, T>, name: &'a str) -> Option<~str> {
+    let d = match ctx.grammar.find(&name) {
+        Some(pat) => diagram(pat),
+        None => return None
+    };
+    let (w, up, down) = measure(&d);
+    let (width, height, y) = (w + 60, up + down + 40, up + 20);
+    let mut out = format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{:u}\" height=\"{:u}\" viewBox=\"0 0 {:u} {:u}\">\n", width, height, width, height);
+    out.push_str(format!("<title>{:s}</title>\n", escape(name)));
+    out.push_str("<style>line, path { fill: none; stroke: black; stroke-width: 2 } rect { fill: #ffc; stroke: black; stroke-width: 2 } " +
+                 "text { font: 13px monospace; text-anchor: middle } .comment { font-style: italic }</style>\n");
+    line(&mut out, 10, y - 8, 10, y + 8);
+    line(&mut out, 10, y, 30, y);
+    draw(&d, 30, y, &mut out);
+    line(&mut out, 30 + w, y, 50 + w, y);
+    line(&mut out, 50 + w, y - 8, 50 + w, y + 8);
+    out.push_str("</svg>\n");
+    Some(out)
+}
+
+// Railroad diagrams for every rule, by name, in the same order as to_ebnf.
+pub fn to_railroads<'a, T:Clone>(ctx: &ParseContext<'a, T>, roots: &[&'a str]) -> ~[(~str, ~str)] {
+    let mut res = ~[];
+    for name in rule_order(ctx, roots).iter() {
+        match to_railroad(ctx, *name) {
+            Some(svg) => res.push((name.to_owned(), svg)),
+            None => ()
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use parse::*;
+    use super::*;
+
+    #[test]
+    fn quotes_of_both_kinds_make_a_special_sequence() {
+        let mut ctx: ParseContext<()> = ParseContext::new();
+        ctx.rule("quotes", ~Literal("it's \"x\""));
+        assert_eq!(to_ebnf(&ctx, &["quotes"]), ~"quotes = ? \"it\\'s \\\"x\\\"\" ? ;\n");
+    }
+
+    #[test]
+    fn empty_choices_can_be_written() {
+        let mut ctx: ParseContext<()> = ParseContext::new();
+        ctx.rule("none", ~Or(~[]));
+        assert_eq!(to_ebnf(&ctx, &["none"]), ~"none = ? nothing ? ;\n");
+        assert!(to_railroad(&ctx, "none").is_some());
+    }
+
+    #[test]
+    fn operators_of_nothing_leave_the_operand() {
+        let mut ctx: ParseContext<()> = ParseContext::new();
+        ctx.rule("ops", ~Precedence(~Literal("x"), ~[]));
+        assert_eq!(to_ebnf(&ctx, &["ops"]), ~"ops = \"x\" ;\n");
+        assert!(to_railroad(&ctx, "ops").is_some());
+    }
+}
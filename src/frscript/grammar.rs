@@ -1,6 +1,5 @@
 use parse::*;
 use std::from_str;
-use std::vec;
 use std::str::*;
 
 #[deriving(Clone)]
@@ -10,9 +9,14 @@ pub enum FRToken {
     Label(~str),
     String(~str),
     Number(f32),
+    Int(i64),
     Bool(bool),
     FRSeq(~[Token<FRToken>]),
-    Expr(~[Token<FRToken>])
+    Expr(~[Token<FRToken>]),
+    Quote(~[Token<FRToken>]),
+    Effect(~[Token<FRToken>], ~[Token<FRToken>]),
+    Def(~str, Option<~Token<FRToken>>, ~Token<FRToken>),
+    If(~Token<FRToken>, ~Token<FRToken>, Option<~Token<FRToken>>)
 }
 
 impl TokenCreator for FRToken {
@@ -27,69 +31,156 @@ impl TokenCreator for FRToken {
     }
 }
 
-fn make_number(s: ~str) -> Result<FRToken, ~str> {
+impl Relocate for FRToken {
+    fn relocate(&self, f: &fn(&LineInfo) -> LineInfo) -> FRToken {
+        fn all(arr: &[Token<FRToken>], f: &fn(&LineInfo) -> LineInfo) -> ~[Token<FRToken>] {
+            arr.iter().map(|x| x.relocate(|l| f(l))).collect()
+        }
+        match *self {
+            FRSeq(ref arr) => FRSeq(all(*arr, |l| f(l))),
+            Expr(ref arr) => Expr(all(*arr, |l| f(l))),
+            Quote(ref arr) => Quote(all(*arr, |l| f(l))),
+            Effect(ref tin, ref tout) => Effect(all(*tin, |l| f(l)), all(*tout, |l| f(l))),
+            Def(ref name, ref ty, ref body) => {
+                let ty = match *ty {
+                    Some(ref t) => Some(~t.relocate(|l| f(l))),
+                    None => None
+                };
+                Def(name.clone(), ty, ~body.relocate(|l| f(l)))
+            }
+            If(ref cond, ref body, ref other) => {
+                let other = match *other {
+                    Some(ref t) => Some(~t.relocate(|l| f(l))),
+                    None => None
+                };
+                If(~cond.relocate(|l| f(l)), ~body.relocate(|l| f(l)), other)
+            }
+            _ => self.clone()
+        }
+    }
+}
+
+fn make_float(s: ~str, _: LineInfo) -> Result<FRToken, ActionError> {
     match from_str::from_str::<f32>(s) {
         Some(x) => Ok(Number(x)),
-        None => Err(~"Failed to parse number")
+        None => Err(ActionError::new("Failed to parse number"))
     }
 }
 
-fn make_string_mid(text: ~str) -> Result<FRToken, ~str> {
+fn make_integer(s: ~str, _: LineInfo) -> Result<FRToken, ActionError> {
+    match from_str::from_str::<i64>(s) {
+        Some(x) => Ok(Int(x)),
+        None => Err(ActionError::new("Integer literal out of range"))
+    }
+}
+
+fn make_string_mid(text: ~str, _: LineInfo) -> Result<FRToken, ActionError> {
     Ok(Unparsed(text))
 }
 
-fn make_string(tok: FRToken) -> Result<FRToken, ~str> {
-    match tok {
+fn make_string(tok: Token<FRToken>) -> Result<FRToken, ActionError> {
+    match tok.value {
         FRSeq(a) => match a[1].value {
             Unparsed(s) => Ok(String(s)),
-            _ => Err(~"Failed to construct string")
+            _ => Err(ActionError::new("Failed to construct string"))
         },
-        _ => Err(~"Failed to construct string")
+        _ => Err(ActionError::new("Failed to construct string"))
     }
 }
 
-fn make_whitespace(_: ~str) -> Result<FRToken, ~str> {
+fn make_whitespace(_: ~str, _: LineInfo) -> Result<FRToken, ActionError> {
     Ok(Whitespace)
 }
 
-fn make_label(s: ~str) -> Result<FRToken, ~str> {
+fn make_label(s: ~str, _: LineInfo) -> Result<FRToken, ActionError> {
     Ok(Label(s))
 }
 
-fn make_expr(tok: FRToken) -> Result<FRToken, ~str> {
-    /* 
-    FRSeq(~[
-        parse::Token<grammar::FRToken>{
-            value: Number(4f32)
-        }, 
-        parse::Token<grammar::FRToken>{
-            value: FRSeq(~[
-                parse::Token<grammar::FRToken>{
-                    value: FRSeq(~[
-                        parse::Token<grammar::FRToken>{
-                            value: Number(4f32), 
-                        }
-                    ])
-                }, 
-                parse::Token<grammar::FRToken>{
-                    value: FRSeq(~[
-                        parse::Token<grammar::FRToken>{
-                            value: Number(4f32), 
-                        }
-                    ])
+// data and impl are kept for the declarations they'll start
+static KEYWORDS: &'static [&'static str] = &["if", "else", "def", "data", "impl"];
+
+fn not_keyword(tok: &Token<FRToken>) -> bool {
+    match tok.value {
+        Label(ref s) => !KEYWORDS.iter().any(|k| *k == s.as_slice()),
+        _ => true
+    }
+}
+
+// The first atom, then one for each of the others with the whitespace before
+// it.
+fn make_expr(tok: Token<FRToken>) -> Result<FRToken, ActionError> {
+    match tok.value {
+        FRSeq([start, Token {value: FRSeq(rest), line: _}]) => {
+            let mut res = ~[start];
+            for x in rest.iter() {
+                match x.value {
+                    FRSeq([ref v]) => res.push(v.clone()),
+                    _ => return Err(ActionError::at(x.line.clone(), "Failed to construct expr"))
                 }
-            ])
+            }
+            Ok(Expr(res))
         }
-    ])
-    */
-    //println!("{:?}", tok);
-    match tok {
-        FRSeq([start, Token {value: FRSeq(rest), line: _}]) => Ok(Expr(vec::append(~[start], rest.map(|x| match x.value {FRSeq([ref v]) => v.clone(), _ => fail!("???")})))),
-        _ => Err(~"Failed to construct expr")
+        _ => Err(ActionError::new("Failed to construct expr"))
+    }
+}
+
+fn make_group(tok: Token<FRToken>) -> Result<FRToken, ActionError> {
+    match tok.value {
+        FRSeq([_, Token {value: Expr(e), line: _}, _]) => Ok(Expr(e)),
+        _ => Err(ActionError::new("Failed to construct group"))
+    }
+}
+
+fn make_quote(tok: Token<FRToken>) -> Result<FRToken, ActionError> {
+    match tok.value {
+        FRSeq([_, Token {value: FRSeq([]), line: _}, _]) => Ok(Quote(~[])),
+        FRSeq([_, Token {value: FRSeq([Token {value: FRSeq([Token {value: Expr(e), line: _}]), line: _}]), line: _}, _]) => Ok(Quote(e)),
+        _ => Err(ActionError::new("Failed to construct quotation"))
     }
 }
 
-/*fn count_ws(s: &str) -> (uint, uint, uint) {
+fn make_effect(tok: Token<FRToken>) -> Result<FRToken, ActionError> {
+    fn types(arr: &[Token<FRToken>]) -> Result<~[Token<FRToken>], ActionError> {
+        let mut res = ~[];
+        for t in arr.iter() {
+            match t.value {
+                FRSeq([ref T]) => res.push(T.clone()),
+                _ => return Err(ActionError::at(t.line.clone(), "Failed to construct stack effect"))
+            }
+        }
+        Ok(res)
+    }
+    match tok.value {
+        FRSeq([_, Token {value: FRSeq(tin), line: _}, _, Token {value: FRSeq(tout), line: _}, _]) => match (types(tin), types(tout)) {
+            (Ok(tin), Ok(tout)) => Ok(Effect(tin, tout)),
+            (Err(e), _) | (_, Err(e)) => Err(e)
+        },
+        _ => Err(ActionError::new("Failed to construct stack effect"))
+    }
+}
+
+fn make_def(tok: Token<FRToken>) -> Result<FRToken, ActionError> {
+    match tok.value {
+        FRSeq([_, Token {value: Label(name), line: _}, Token {value: FRSeq(effect), line: _}, _, body]) => Ok(Def(name, effect.head_opt().map(|x| ~x.clone()), ~body)),
+        _ => Err(ActionError::new("Failed to construct definition"))
+    }
+}
+
+fn make_if(tok: Token<FRToken>) -> Result<FRToken, ActionError> {
+    match tok.value {
+        FRSeq([_, cond, _, body]) => Ok(If(~cond, ~body, None)),
+        _ => Err(ActionError::new("Failed to construct if"))
+    }
+}
+
+fn make_else(tok: Token<FRToken>) -> Result<FRToken, ActionError> {
+    match tok.value {
+        FRSeq([Token {value: If(cond, body, None), line: _}, Token {value: FRSeq([_, _, other]), line: _}]) => Ok(If(cond, body, Some(~other))),
+        _ => Err(ActionError::new("Failed to construct else"))
+    }
+}
+
+fn count_ws(s: &str) -> (uint, uint, uint) {
     let mut spaces = 0;
     let mut tabs = 0;
     let mut i = 0;
@@ -105,25 +196,94 @@ fn make_expr(tok: FRToken) -> Result<FRToken, ~str> {
     (spaces, tabs, i)
 }
 
-fn match_block(ctx: &mut ParseContext<FRToken>, s: &str) -> Result<(FRToken, uint), ~str> {
-    let (nspaces, ntabs, i) = count_ws(s);
-    let mut i = i;
+// A block is either the rest of the current line, or the lines following it
+// at the indentation of the first of them. Blank lines are skipped, and the
+// block ends at the first line that is indented less.
+fn match_block(ctx: &ParseContext<FRToken>, input: &str, position: uint) -> Result<Token<FRToken>, SyntaxError> {
+    let expr = ctx.grammar.get(& &"expr");
+    let s = input.slice_from(position);
+    let err = |msg: ~str, start: uint, end: uint| {
+        Err(SyntaxError {pats: ~[~"block"], instead: None, user_msg: Some(msg), line: ctx.line_info(input, start+position, end+position), is_malformed: true})
+    };
+    let (_, _, start) = count_ws(s);
+    // a line ending in "\r\n" ends at the "\n" like any other
+    let start = if s.slice_from(start).starts_with("\r\n") { start + 1 } else { start };
+    ctx.looked_at(position + start + 1);
+    if start < s.len() && s.char_at(start) != '\n' {
+        return parse_at(ctx, expr, input, start + position)
+    }
+    let mut indent = None;
     let mut res = ~[];
-    loop {
-        let res = parse(ctx, &Rule("expr"), s.slice_from(i), i);
-        let (spaces, tabs, offset) = count_ws(s.slice_from(i));
-        if spaces < nspaces || tabs < ntabs {
-            break;
+    let mut i = start;
+    let mut end = start;
+    while i < s.len() {
+        let line = s.slice_from(i + 1);
+        let (spaces, tabs, offset) = count_ws(line);
+        ctx.looked_at(position + i + 1 + offset + 1);
+        if offset == line.len() || line.char_at(offset) == '\n' || line.char_at(offset) == '\r' {
+            i += 1 + offset;
+            continue
+        }
+        match indent {
+            None if spaces + tabs == 0 => break,
+            None => indent = Some((spaces, tabs)),
+            Some((nspaces, ntabs)) => if spaces < nspaces || tabs < ntabs {
+                break
+            } else if spaces > nspaces || tabs > ntabs {
+                return err(~"Unexpected indentation", i + 1, i + 1 + offset)
+            }
+        }
+        let linestart = i + 1 + offset;
+        match parse_at(ctx, expr, input, linestart + position) {
+            Ok(x) => {
+                end = x.line.endslice - position;
+                res.push(x);
+            }
+            Err(e) => return Err(e)
+        }
+        let (_, _, trailing) = count_ws(s.slice_from(end));
+        i = end + trailing;
+        ctx.looked_at(position + i + 1);
+        if i < s.len() && s.char_at(i) != '\n' {
+            return err(~"Expected end of line", i, i + 1)
+        }
+    }
+    if res.len() < 1 {
+        return err(~"Expected an indented block", start, start)
+    }
+    let mut body = ~[];
+    for x in res.iter() {
+        match x.value {
+            Expr(ref arr) => body.push_all(*arr),
+            _ => body.push(x.clone())
         }
-        i += offset;
     }
-}*/
+    Ok(Token {value: Expr(body), line: ctx.line_info(input, position, end + position)})
+}
 
-fn make_bool(tok: FRToken) -> Result<FRToken, ~str> {
-    match tok {
+fn make_bool(tok: Token<FRToken>) -> Result<FRToken, ActionError> {
+    match tok.value {
         Unparsed(~"true") => Ok(Bool(true)),
         Unparsed(~"false") => Ok(Bool(false)),
-        _ => Err(~"Failed to parse boolean")
+        _ => Err(ActionError::new("Failed to parse boolean"))
+    }
+}
+
+fn make_statement(tok: Token<FRToken>) -> Result<FRToken, ActionError> {
+    match tok.value {
+        FRSeq([stat]) => Ok(stat.value),
+        _ => Err(ActionError::new("Failed to construct statement"))
+    }
+}
+
+// One token per statement, with the text of any that failed to parse.
+fn make_file(tok: Token<FRToken>) -> Result<FRToken, ActionError> {
+    match tok.value {
+        FRSeq([Token {value: FRSeq(items), line: _}]) => Ok(FRSeq(items.iter().map(|x| match x.value {
+            FRSeq([ref stat]) => stat.clone(),
+            _ => x.clone()
+        }).collect())),
+        _ => Err(ActionError::new("Failed to construct file"))
     }
 }
 
@@ -131,30 +291,147 @@ pub fn grammar() -> ParseContext<FRToken> {
     let mut ctx = ParseContext::new();
     let sws = || ~Rule("sws");
     let ws = || ~Rule("ws");
-    ctx.rule("space",       ~Set(" \t\n".iter().collect()));
-    ctx.rule("ws",          ~Build(~More(~Rule("space")), make_whitespace));
-    ctx.rule("sws",         ~Build(~MoreThan(1, ~Rule("space")), make_whitespace));
+    let hws = || ~Rule("hws");
+    let hsws = || ~Rule("hsws");
+    ctx.rule("space",       ~Set(" \t\r\n".iter().collect()));
+    ctx.rule("hspace",      ~Set(" \t".iter().collect()));
+    ctx.rule("ws",          ~Build(~More(~Rule("space")), Builder(make_whitespace)));
+    ctx.rule("sws",         ~Build(~MoreThan(1, ~Rule("space")), Builder(make_whitespace)));
+    ctx.rule("hws",         ~Build(~More(~Rule("hspace")), Builder(make_whitespace)));
+    ctx.rule("hsws",        ~Build(~MoreThan(1, ~Rule("hspace")), Builder(make_whitespace)));
     ctx.rule("digit",       ~Range('0','9'));
     ctx.rule("digits",      ~MoreThan(1, ~Rule("digit")));
-    ctx.rule("alpha",       ~Range('a','z') + ~Range('A','Z'));
-    ctx.rule("number",      ~Build(~LessThan(1, ~Literal("-")) * ~Rule("digits") * ~LessThan(1, ~Literal(".") * ~Rule("digits")) * ~LessThan(1, ~Set("eE".iter().collect()) * ~LessThan(1, ~Literal("-")) * ~Rule("digits")), make_number));
+    ctx.rule("alpha",       ~Class(Letter));
+    ctx.rule("number",      ~Rule("float") + ~Rule("integer"));
+    ctx.rule("float",       ~Build(~LessThan(1, ~Literal("-")) * ~Rule("digits") * (~Rule("fraction") * ~LessThan(1, ~Rule("exponent")) + ~Rule("exponent")), Builder(make_float)));
+    ctx.rule("integer",     ~Build(~LessThan(1, ~Literal("-")) * ~Rule("digits"), Builder(make_integer)));
+    ctx.rule("fraction",    ~Literal(".") * ~Rule("digits"));
+    ctx.rule("exponent",    ~Set("eE".iter().collect()) * ~LessThan(1, ~Literal("-")) * ~Rule("digits"));
     ctx.rule("symbol",      ~Set("~!@#$%^&*_-+=/<>'".iter().collect()));
-    ctx.rule("atom",        ~Build((~Rule("alpha") + ~Rule("digit") + ~Rule("symbol"))[1], make_label));
-    ctx.rule("string_mid",  ~Build(~More(~Diff(~Literal("\\\"") + ~Chars(1), ~Literal("\""))), make_string_mid));
-    ctx.rule("string",      ~Map(~Literal("\"") * ~Rule("string_mid") * ~Literal("\""), make_string));
-    ctx.rule("toplevel",    ~Rule("def") + ~Rule("data") + ~Rule("impl"));
+    ctx.rule("atom",        ~Predicate(~Build((~Rule("alpha") + ~Rule("digit") + ~Rule("symbol"))[1], Builder(make_label)), not_keyword));
+    ctx.rule("string_mid",  ~Build(~More(~Diff(~Literal("\\\"") + ~Chars(1), ~Literal("\""))), Builder(make_string_mid)));
+    ctx.rule("string",      ~Map(~Literal("\"") * ~Rule("string_mid") * ~Literal("\""), Mapper(make_string)));
+    ctx.rule("toplevel",    ~Rule("def"));
     ctx.rule("repl-stat",   ~Rule("toplevel") + ~Rule("expr"));
-    ctx.rule("expr",        ~Map(~Rule("expratom") * ~More(sws() * ~Rule("expratom")), make_expr));
-    ctx.rule("expratom",    ~Rule("literal") + ~Rule("atom") + ~Rule("control"));
+    ctx.rule("file",        ~Map(ws() * ~More(~Recover(~Rule("statement"), ~Rule("resync")) * ws()), Mapper(make_file)));
+    ctx.rule("statement",   ~Map(~Rule("repl-stat") * ~Rule("eol"), Mapper(make_statement)));
+    ctx.rule("eol",         ~Build(hws() * (~Literal("\r\n") + ~Literal("\n") + ~Eof), Builder(make_whitespace)));
+    // after an error, carry on from the next line that isn't indented
+    ctx.rule("resync",      (~Literal("\r\n") + ~Literal("\n")) * ~And(~Diff(~Chars(1), ~Rule("space"))));
+    // atoms have to be separated, and whitespace that isn't followed by
+    // another atom ends the expression, leaving it to whatever comes next
+    ctx.rule("expr",        ~Map(~Rule("expratom") * ~More(~Diff(hsws() * ~Rule("expratom"), hws() * ~Not(~Rule("expratom")))), Mapper(make_expr)));
+    ctx.rule("expratom",    ~Rule("literal") + ~Rule("group") + ~Rule("quote") + ~Rule("control") + ~Rule("atom"));
+    ctx.rule("quote",       ~Map(~Literal("[") * ws() * ~LessThan(1, ~Rule("expr") * hws()) * ~Literal("]"), Mapper(make_quote)));
+    ctx.rule("group",       ~Map(~Literal("(") * ws() * ~Rule("expr") * ws() * ~Literal(")"), Mapper(make_group)));
     ctx.rule("literal",     ~Rule("number") + ~Rule("string") + ~Rule("boolean"));
-    ctx.rule("boolean",     ~Map(~Literal("true") + ~Literal("false"), make_bool));
+    ctx.rule("boolean",     ~Map(~Literal("true") + ~Literal("false"), Mapper(make_bool)));
     ctx.rule("control",     ~Rule("if"));
-    ctx.rule("if",          ~Literal("if") * ~Rule("sws") * ~Rule("expr") * ~Rule("sws") * ~Literal(":") * ~Rule("block"));
-    ctx.rule("def",         ~Literal("def") * sws() * ~Rule("atom") * ~Literal(":") * sws() * ~Rule("block"));
-    ctx.rule("data",        ~Literal("data") * sws() * ~Rule("atom") * ws() * ~Literal("::") * ws() * ~Rule("typespec"));
-    ctx.rule("impl",        ~Literal("impl") * sws() * ~Rule("atom") * sws() * ~Rule("atom") * ~Literal(":") * ~Rule("implblock"));
-    //ctx.rule("block",       ~Match(match_block));
+    ctx.rule("if",          ~Map(~Rule("if-head") * ~Rule("else"), Mapper(make_else)) + ~Rule("if-head"));
+    ctx.rule("if-head",     ~Map(~Literal("if") * sws() * ~Rule("expr") * hws() * ~Literal(":") * ~Rule("block"), Mapper(make_if)));
+    ctx.rule("else",        ws() * ~Literal("else") * ~Literal(":") * ~Rule("block"));
+    ctx.rule("def",         ~Map(~Literal("def") * sws() * ~Rule("atom") * ws() * ~LessThan(1, ~Rule("effect")) * ~Literal(":") * ~Rule("block"), Mapper(make_def)));
+    ctx.rule("effect",      ~Map(~Literal("(") * ws() * ~Rule("typelist") * ~Literal("--") * ws() * ~Rule("typelist") * ~Literal(")"), Mapper(make_effect)));
+    ctx.rule("typelist",    ~More((~Rule("typename") + ~Rule("effect")) * ws()));
+    ctx.rule("typename",    ~Build(~Rule("alpha")[1], Builder(make_label)));
+    ctx.rule("block",       ~Match(match_block));
 
     ctx
 }
 
+#[cfg(test)]
+mod test {
+    use std::io;
+    use parse::*;
+    use super::*;
+
+    // Whether rule matches the whole of text.
+    fn parses(rule: &'static str, text: &str) -> bool {
+        let ctx = grammar();
+        match parse_at(&ctx, ctx.grammar.get(&rule), text, 0) {
+            Ok(x) => x.line.endslice == text.len(),
+            Err(_) => false
+        }
+    }
+
+    #[test]
+    fn grammar_is_valid() {
+        let errs = grammar().validate(&["file", "repl-stat"]);
+        assert!(errs.len() == 0, errs.map(|e| e.to_str()).connect("\n"));
+    }
+
+    #[test]
+    fn keywords_are_not_atoms() {
+        assert!(parses("atom", "iffy"));
+        assert!(parses("atom", "define"));
+        assert!(!parses("atom", "if"));
+        assert!(!parses("atom", "def"));
+        assert!(!parses("expr", "1 else"));
+    }
+
+    // Where each statement streamed from text starts, and whether it parsed.
+    fn streamed(text: &str) -> ~[(int, uint, bool)] {
+        let mut ctx = grammar();
+        ctx.memoize();
+        io::with_str_reader(text, |reader| {
+            let stream = Stream::new(&ctx, ctx.grammar.get(& &"statement"), ctx.grammar.get(& &"ws"), ctx.grammar.get(& &"resync"), reader);
+            stream.map(|res| match res {
+                Ok(x) => (x.line.line, x.line.startcol, true),
+                Err(e) => (e.line.line, e.line.startcol, false)
+            }).collect()
+        })
+    }
+
+    #[test]
+    fn statements_stream_from_a_reader() {
+        let text = "1 2 +\ndef f:\n  3\n  4\nf\n1 )\n2\n";
+        // the definition runs over three lines, and the error is where the
+        // statement went wrong, after which the next line is parsed
+        assert_eq!(streamed(text), ~[(0, 0, true), (1, 0, true), (4, 0, true), (5, 2, false), (6, 0, true)]);
+    }
+
+    #[test]
+    fn atoms_need_whitespace_between_them() {
+        assert!(parses("expr", "2 dup"));
+        assert!(!parses("expr", "2dup"));
+    }
+
+    #[test]
+    fn whitespace_can_end_a_quotation() {
+        assert!(parses("quote", "[ 1 + ]"));
+        assert!(parses("quote", "[1 +]"));
+        assert!(parses("quote", "[ ]"));
+    }
+
+    #[test]
+    fn lines_can_end_in_crlf() {
+        let ctx = grammar();
+        let (res, errors) = parse_all(&ctx, ctx.grammar.get(& &"file"), "def f:\r\n  1\r\n  2 +\r\n\r\nf\r\n");
+        assert!(res.is_some());
+        assert_eq!(errors.len(), 0);
+        // and an error only skips to the end of its line
+        let (_, errors) = parse_all(&ctx, ctx.grammar.get(& &"file"), "1 )\r\n2\r\n");
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn edited_input_parses_as_if_fresh() {
+        let mut ctx = grammar();
+        ctx.memoize();
+        let old = "1 2 +\ndef f:\n  3 4\nf f\n";
+        parse_all(&ctx, ctx.grammar.get(& &"file"), old);
+        // puts a line break in the first line, so the rest of it moves down
+        // and along and every line after it moves down
+        let new = ctx.edit(old, 2, 3, "7\n8");
+        let edited = parse_all(&ctx, ctx.grammar.get(& &"file"), new);
+        let fresh = grammar();
+        let expected = parse_all(&fresh, fresh.grammar.get(& &"file"), new);
+        assert_eq!(format!("{:?}", edited), format!("{:?}", expected));
+    }
+
+    #[test]
+    fn whitespace_can_end_a_group() {
+        assert!(parses("group", "(1 2 )"));
+        assert!(parses("group", "(1 2\n)"));
+    }
+}
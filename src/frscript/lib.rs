@@ -12,9 +12,12 @@
 #[feature(struct_variant)];
 
 pub mod parse;
+pub mod peg;
+pub mod export;
 pub mod grammar;
 pub mod ast;
-pub mod eval;
+pub mod compiler;
+pub mod vm;
 pub mod context;
 pub mod typechecker;
 pub mod macro;
@@ -1,5 +1,6 @@
 use std::str::*;
 use std::hashmap::*;
+use std::io::{Reader, ReaderUtil};
 
 #[deriving(Clone)]
 pub enum Pattern<'self, T> {
@@ -11,6 +12,7 @@ pub enum Pattern<'self, T> {
     Range(char, char),
     Chars(uint),
     Set(~[char]),
+    Class(CharClass),
     More(~Pattern<'self, T>),
     MoreThan(uint, ~Pattern<'self, T>),
     Exactly(uint, ~Pattern<'self, T>),
@@ -18,15 +20,83 @@ pub enum Pattern<'self, T> {
     Seq(~[Pattern<'self, T>]),
     Or(~[Pattern<'self, T>]),
     Diff(~Pattern<'self, T>, ~Pattern<'self, T>),
+    // operands separated by operators, nested by precedence so that each
+    // operator's token is a sequence of its left operand, itself and its
+    // right operand
+    Precedence(~Pattern<'self, T>, ~[Operator<'self, T>]),
 
     // matches but doesn't consume
     And(~Pattern<'self, T>),
+    // matches where the pattern doesn't, without consuming
+    Not(~Pattern<'self, T>),
+    Eof,
     Always(T),
+    // matches the pattern only if the function accepts its token
+    Predicate(~Pattern<'self, T>, extern fn(&Token<T>) -> bool),
+    // names what the pattern matched, until the rule it's in returns
+    Capture(&'self str, ~Pattern<'self, T>),
+    // matches the same text as the capture with that name did
+    Backref(&'self str),
 
-    // parsing
-    //Match(extern fn(&'self mut ParseContext<T>, &'self str) -> Result<(T, uint), ~str>),
-    Build(~Pattern<'self, T>, extern fn(~str) -> Result<T, ~str>),
-    Map(~Pattern<'self, T>, extern fn(T) -> Result<T, ~str>)
+    // on failure, records the error and skips ahead to where the second
+    // pattern matches, so that parsing can carry on
+    Recover(~Pattern<'self, T>, ~Pattern<'self, T>),
+
+    // parsing; a Match function gets the whole input and the position to
+    // match at, and tells the context with looked_at if it reads past the
+    // end of what it returns
+    Match(extern fn(&ParseContext<'self, T>, &str, uint) -> Result<Token<T>, SyntaxError>),
+    Build(~Pattern<'self, T>, Builder<T>),
+    Map(~Pattern<'self, T>, Mapper<T>)
+}
+
+#[deriving(Clone, Eq)]
+pub enum Assoc {
+    Left,
+    Right
+}
+
+// Higher precedences bind tighter.
+#[deriving(Clone)]
+pub struct Operator<'self, T> {
+    pat: Pattern<'self, T>,
+    prec: uint,
+    assoc: Assoc
+}
+
+// Parse actions are closures, so they can carry whatever the grammar needs,
+// like a symbol table. A Builder gets the text matched and where it was, and
+// a Mapper gets the token, whose children have their own spans.
+pub struct Builder<T>(@fn(~str, LineInfo) -> Result<T, ActionError>);
+pub struct Mapper<T>(@fn(Token<T>) -> Result<T, ActionError>);
+
+impl<T> Clone for Builder<T> {
+    fn clone(&self) -> Builder<T> {
+        Builder(**self)
+    }
+}
+
+impl<T> Clone for Mapper<T> {
+    fn clone(&self) -> Mapper<T> {
+        Mapper(**self)
+    }
+}
+
+// Why an action couldn't make a token. Without a span the error covers
+// everything the action was given.
+#[deriving(Clone)]
+pub struct ActionError {
+    msg: ~str,
+    line: Option<LineInfo>
+}
+
+impl ActionError {
+    pub fn new(msg: &str) -> ActionError {
+        ActionError {msg: msg.to_owned(), line: None}
+    }
+    pub fn at(line: LineInfo, msg: &str) -> ActionError {
+        ActionError {msg: msg.to_owned(), line: Some(line)}
+    }
 }
 
 impl<'self,T:Clone> ToStr for Pattern<'self,T> {
@@ -37,6 +107,7 @@ impl<'self,T:Clone> ToStr for Pattern<'self,T> {
             Range(x,y)      => format!("%{:x}-{:x}", x as uint, y as uint),
             Chars(n)        => format!("any*{}", n),
             Set(a)          => format!("<One of {:s}>", a.to_str()),
+            Class(c)        => format!("<{:s}>", c.to_str()),
             More(p)         => format!("{:s}*", p.to_str()),
             MoreThan(n, p)  => format!("{:s}*{:u} {:s}*", p.to_str(), n, p.to_str()),
             Exactly(n, p)   => format!("{:s}*{:u}", p.to_str(), n),
@@ -44,9 +115,53 @@ impl<'self,T:Clone> ToStr for Pattern<'self,T> {
             Seq(a)          => "(" + a.map(|x| x.to_str()).connect(" ") + ")",
             Or(a)           => "(" + a.map(|x| x.to_str()).connect(" | ") + ")",
             Diff(p1, p2)    => format!("({:s} - {:s})", p1.to_str(), p2.to_str()),
+            Precedence(p, ops) => "(" + p.to_str() + " with " + ops.map(|x| x.pat.to_str()).connect(" | ") + ")",
+            Recover(p, _)   => p.to_str(),
             Build(p, _)     => p.to_str(),
             Map(p, _)       => p.to_str(),
-            _               => ~"NYI"
+            And(p)          => format!("&{:s}", p.to_str()),
+            Not(p)          => format!("!{:s}", p.to_str()),
+            Eof             => ~"EOF",
+            Always(_)       => ~"<always>",
+            Predicate(p, _) => format!("{:s}?", p.to_str()),
+            Capture(n, p)   => format!("{:s}:{:s}", n, p.to_str()),
+            Backref(n)      => format!("${:s}", n),
+            Match(_)        => ~"<match>",
+        }
+    }
+}
+
+// Unicode character classes, for text that isn't all ASCII.
+#[deriving(Clone, Eq)]
+pub enum CharClass {
+    Letter,
+    Digit,
+    Whitespace,
+    // characters that can start or continue an identifier, as in UAX #31
+    XidStart,
+    XidContinue
+}
+
+impl CharClass {
+    pub fn contains(&self, c: char) -> bool {
+        match *self {
+            Letter      => c.is_alphabetic(),
+            Digit       => c.is_digit(),
+            Whitespace  => c.is_whitespace(),
+            XidStart    => c.is_XID_start(),
+            XidContinue => c.is_XID_continue()
+        }
+    }
+}
+
+impl ToStr for CharClass {
+    fn to_str(&self) -> ~str {
+        match *self {
+            Letter      => ~"letter",
+            Digit       => ~"digit",
+            Whitespace  => ~"whitespace",
+            XidStart    => ~"identifier start",
+            XidContinue => ~"identifier character"
         }
     }
 }
@@ -56,6 +171,13 @@ pub trait TokenCreator {
     fn raw(~str) -> Self;
 }
 
+// For token types that ParseContext::edit and Stream can move along the
+// input, which they do with every token inside one too.
+pub trait Relocate {
+    // A copy with the spans of any tokens inside it passed through f.
+    fn relocate(&self, f: &fn(&LineInfo) -> LineInfo) -> Self;
+}
+
 impl<'self, T:Clone> Mul<~Pattern<'self, T>, ~Pattern<'self, T>> for ~Pattern<'self, T> {
     fn mul(&self, rhs: &~Pattern<'self, T>) -> ~Pattern<'self, T> {
         match (*self.clone(), *rhs.clone()) {
@@ -96,26 +218,81 @@ pub struct LineInfo {
 }
 
 impl LineInfo {
+    // Scans text from the start, so parsing uses a LineIndex kept for the
+    // whole input instead.
     pub fn new(text: &str, start: uint, end: uint) -> LineInfo {
-        fn compute_line(text: &str, max: uint) -> (int, uint) {
-            let mut line = 0;
-            let mut offset = 0;
-            let mut temp = 0;
-            for c in text.iter() {
-                if c == '\n' {
-                    line += 1;
-                    offset += temp;
-                    temp = 0;
-                }
-                temp += 1;
-                if temp >= max {
-                    break;
+        LineIndex::new(text).info(text, start, end)
+    }
+}
+
+// Where each line of a text starts, in bytes and in characters, so that a
+// span's line and columns can be found without counting from the start.
+pub struct LineIndex {
+    // the text indexed, to tell whether a later input is the same one
+    ptr: *u8,
+    len: uint,
+    starts: ~[uint],
+    chars: ~[uint]
+}
+
+impl LineIndex {
+    pub fn new(text: &str) -> LineIndex {
+        let mut starts = ~[0];
+        let mut chars = ~[0];
+        let mut n = 0;
+        for (i, c) in text.char_offset_iter() {
+            n += 1;
+            if c == '\n' {
+                starts.push(i + 1);
+                chars.push(n);
+            }
+        }
+        LineIndex {ptr: text.as_imm_buf(|p, _| p), len: text.len(), starts: starts, chars: chars}
+    }
+    pub fn indexes(&self, text: &str) -> bool {
+        self.len == text.len() && self.ptr == text.as_imm_buf(|p, _| p)
+    }
+    // The line offset is on, by binary search.
+    fn line_of(&self, offset: uint) -> uint {
+        let mut lo = 0;
+        let mut hi = self.starts.len();
+        while hi - lo > 1 {
+            let mid = (lo + hi) / 2;
+            if self.starts[mid] <= offset {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        lo
+    }
+    // Columns count characters rather than bytes. Offsets past the end of
+    // the text, which errors at the end of input have, count one each. The
+    // end column counts from the start of the line the span starts on.
+    pub fn info(&self, text: &str, start: uint, end: uint) -> LineInfo {
+        // characters from the start of line to offset, which is on it
+        let column = |line: uint, to: uint| {
+            let offset = self.starts[line];
+            let mut col = 0;
+            let mut past = true;
+            for (i, _) in text.slice_from(offset).char_offset_iter() {
+                if offset + i >= to {
+                    past = false;
+                    break
                 }
+                col += 1;
             }
-            (line, offset)
+            if past && to > text.len() { col + to - text.len() } else { col }
+        };
+        let line = self.line_of(start);
+        let endline = self.line_of(end);
+        LineInfo {
+            line: line as int,
+            startcol: column(line, start),
+            endcol: self.chars[endline] - self.chars[line] + column(endline, end),
+            startslice: start,
+            endslice: end
         }
-        let (line, offset) = compute_line(text, start);
-        LineInfo {line: line, startcol: start-offset, endcol: end-offset, startslice: start, endslice: end}
     }
 }
 
@@ -136,6 +313,13 @@ pub struct Token<T> {
     line: LineInfo
 }
 
+impl<T:Relocate> Token<T> {
+    pub fn relocate(&self, f: &fn(&LineInfo) -> LineInfo) -> Token<T> {
+        Token {value: self.value.relocate(|l| f(l)), line: f(&self.line)}
+    }
+}
+
+#[deriving(Clone)]
 pub struct SyntaxError {
     pats: ~[~str],
     instead: Option<~str>,
@@ -144,13 +328,14 @@ pub struct SyntaxError {
     is_malformed: bool
 }
 
-impl ToStr for SyntaxError {
-    fn to_str(&self) -> ~str {
+impl SyntaxError {
+    // The error without its location, for callers that report it themselves.
+    pub fn message(&self) -> ~str {
         fn pretty_arr(arr: ~[~str]) -> ~str {
             let len = arr.len();
             let mut s = arr[0].clone();
             if len > 2 {
-                for i in range(1, len - 2) {
+                for i in range(1, len - 1) {
                     s.push_str(", ");
                     s.push_str(arr[i]);
                 }
@@ -161,8 +346,7 @@ impl ToStr for SyntaxError {
                 s
             }
         }
-        let mut err = self.line.to_str();
-        err.push_str(format!(" Expected {}", pretty_arr(self.pats.clone())));
+        let mut err = format!("Expected {}", pretty_arr(self.pats.clone()));
         match self.instead.clone() {
             Some(x) => err.push_str(format!(", got {}", x)),
             None => ()
@@ -175,60 +359,593 @@ impl ToStr for SyntaxError {
     }
 }
 
+impl ToStr for SyntaxError {
+    fn to_str(&self) -> ~str {
+        self.line.to_str() + " " + self.message()
+    }
+}
+
 pub struct ParseContext<'self, T> {
     grammar: HashMap<&'self str, Pattern<'self, T>>,
-    variables: HashMap<~str, Token<T>>,
+    // what each Capture matched, and the bindings they replaced, latest
+    // last, so they can be undone when the rule that made them returns
+    variables: @mut HashMap<~str, Token<T>>,
+    bound: @mut ~[(~str, Option<Token<T>>)],
+    // the oldest binding a Backref in the rule being parsed has used
+    depends: @mut uint,
+    // results of rules already tried, by rule name and position
+    memo: Option<@mut HashMap<(~str, uint), Memo<T>>>,
+    // how far into the input the rule being parsed has looked so far
+    reach: @mut uint,
+    // rules being parsed right now, with the result a left recursive call
+    // gets back and whether one has happened
+    active: @mut HashMap<(~str, uint), (Result<Token<T>, SyntaxError>, bool)>,
+    // errors skipped over by Recover patterns
+    errors: @mut ~[SyntaxError],
+    // where the parser got furthest before failing, and what it wanted there
+    farthest: @mut Farthest,
+    // every rule tried, when tracing
+    trace: Option<@mut ~[Trace]>,
+    // where the lines of the input being parsed start
+    lines: @mut Option<LineIndex>,
 }
 
-impl<'self, T> ParseContext<'self, T> {
+#[deriving(Clone)]
+pub enum Trace {
+    // rule, position
+    Entered(~str, uint),
+    // rule, start, end, the token it made
+    Matched(~str, uint, uint, ~str),
+    // rule, start, error
+    Failed(~str, uint, ~str)
+}
+
+#[deriving(Clone)]
+pub struct Farthest {
+    pos: uint,
+    pats: ~[~str]
+}
+
+impl Farthest {
+    // Notes that what was expected at pos, if that's as far as anything
+    // got.
+    pub fn note(&mut self, pos: uint, what: ~str) {
+        if pos > self.pos {
+            self.pos = pos;
+            self.pats = ~[what];
+        } else if pos == self.pos && !self.pats.contains(&what) {
+            self.pats.push(what);
+        }
+    }
+    // Notes everything other has, as if it had been noted here instead.
+    pub fn merge(&mut self, other: &Farthest) {
+        for what in other.pats.iter() {
+            self.note(other.pos, what.clone());
+        }
+    }
+}
+
+// What parsing a rule at some position came to, with everything it did that
+// a later parse there has to do again without parsing: how far into the
+// input it looked, the errors Recover patterns skipped, and what it expected
+// where it got furthest.
+#[deriving(Clone)]
+pub struct Memo<T> {
+    res: Result<Token<T>, SyntaxError>,
+    reach: uint,
+    errors: ~[SyntaxError],
+    farthest: Farthest
+}
+
+impl<'self, T:'static+Clone> ParseContext<'self, T> {
     pub fn new() -> ParseContext<'self, T> {
-        ParseContext {grammar: HashMap::new(), variables: HashMap::new()}
+        ParseContext {grammar: HashMap::new(), variables: @mut HashMap::new(), bound: @mut ~[], depends: @mut std::uint::max_value, memo: None, reach: @mut 0, active: @mut HashMap::new(), errors: @mut ~[], farthest: @mut Farthest {pos: 0, pats: ~[]}, trace: None, lines: @mut None}
     }
     pub fn rule(&mut self, name: &'self str, rule: ~Pattern<'self, T>) {
         self.grammar.insert(name, *rule);
     }
+    // Remembers the result of each rule at each position, so that
+    // backtracking never parses the same rule at the same place twice. The
+    // table only holds for one input: call forget() before parsing another.
+    pub fn memoize(&mut self) {
+        self.memo = Some(@mut HashMap::new());
+    }
+    pub fn forget(&self) {
+        match self.memo {
+            Some(memo) => memo.clear(),
+            None => ()
+        }
+    }
+    // For Match patterns: notes that the input was looked at up to end, so
+    // that an edit before there reparses whatever did. The end of the token
+    // returned counts already.
+    pub fn looked_at(&self, end: uint) {
+        if end > *self.reach {
+            *self.reach = end;
+        }
+    }
+    // Where a span of input is, using the line index when it's for input.
+    pub fn line_info(&self, input: &str, start: uint, end: uint) -> LineInfo {
+        match *self.lines {
+            Some(ref index) if index.indexes(input) => index.info(input, start, end),
+            _ => LineInfo::new(input, start, end)
+        }
+    }
+    // Starts recording every rule the parser tries, for trace_log and
+    // trace_json. Any earlier trace is thrown away.
+    pub fn trace(&mut self) {
+        self.trace = Some(@mut ~[]);
+    }
+    // The trace with each rule's result lined up under it and the rules it
+    // tried indented in between.
+    pub fn trace_log(&self) -> ~str {
+        let mut out = ~"";
+        let mut depth = 0;
+        for ev in self.traced().iter() {
+            let line = match *ev {
+                Entered(ref rule, pos) => {
+                    depth += 1;
+                    format!("{:s} @{:u}", *rule, pos)
+                }
+                Matched(ref rule, start, end, ref tok) => {
+                    depth -= 1;
+                    format!("{:s} @{:u}-{:u} = {:s}", *rule, start, end, *tok)
+                }
+                Failed(ref rule, start, ref e) => {
+                    depth -= 1;
+                    format!("{:s} @{:u} failed: {:s}", *rule, start, *e)
+                }
+            };
+            let indent = match *ev {
+                Entered(_, _) => depth - 1,
+                _ => depth
+            };
+            for _ in range(0, indent) {
+                out.push_str("  ");
+            }
+            out.push_str(line);
+            out.push_char('\n');
+        }
+        out
+    }
+    // The trace as a JSON array of events, for viewers.
+    pub fn trace_json(&self) -> ~str {
+        let events: ~[~str] = self.traced().iter().map(|ev| match *ev {
+            Entered(ref rule, pos) =>
+                "{\"event\": \"enter\", \"rule\": " + json_str(*rule) + format!(", \"pos\": {:u}", pos) + "}",
+            Matched(ref rule, start, end, ref tok) =>
+                "{\"event\": \"match\", \"rule\": " + json_str(*rule) + format!(", \"start\": {:u}, \"end\": {:u}, \"token\": ", start, end) + json_str(*tok) + "}",
+            Failed(ref rule, start, ref e) =>
+                "{\"event\": \"fail\", \"rule\": " + json_str(*rule) + format!(", \"start\": {:u}, \"error\": ", start) + json_str(*e) + "}"
+        }).collect();
+        "[\n  " + events.connect(",\n  ") + "\n]\n"
+    }
+    fn traced(&self) -> ~[Trace] {
+        match self.trace {
+            Some(t) => (*t).clone(),
+            None => ~[]
+        }
+    }
+    // Checks the grammar for mistakes that would otherwise only show up
+    // while parsing: rules that refer to rules which don't exist, rules that
+    // can't be reached from any of the given start rules, and repetitions of
+    // something that can match without consuming input, which never end.
+    // What Match functions parse can't be seen here: a rule only they use
+    // counts as unreachable unless it's given as a start rule too, and one
+    // they use that doesn't exist is only found when they run.
+    pub fn validate(&self, roots: &[&'self str]) -> ~[GrammarError] {
+        let mut errs = ~[];
+        for name in roots.iter() {
+            if !self.grammar.contains_key(name) {
+                errs.push(GrammarError {rule: name.to_owned(), msg: ~"Start rule is not defined"});
+            }
+        }
+        for (name, pat) in self.grammar.iter() {
+            each_rule(pat, |r| if !self.grammar.contains_key(&r) {
+                errs.push(GrammarError {rule: name.to_owned(), msg: format!("Refers to undefined rule {:s}", r)});
+            });
+        }
+        let mut seen = HashSet::new();
+        let mut todo = roots.to_owned();
+        while todo.len() > 0 {
+            let name = todo.pop();
+            if seen.contains(&name) {
+                continue
+            }
+            seen.insert(name);
+            match self.grammar.find(&name) {
+                Some(pat) => each_rule(pat, |r| todo.push(r)),
+                None => ()
+            }
+        }
+        for (name, _) in self.grammar.iter() {
+            if !seen.contains(name) {
+                errs.push(GrammarError {rule: name.to_owned(), msg: ~"Not reachable from any start rule"});
+            }
+        }
+        // which rules can match the empty string, found by iterating until
+        // nothing changes
+        let mut empty = HashSet::new();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (name, pat) in self.grammar.iter() {
+                if !empty.contains(name) && nullable(pat, &empty) {
+                    empty.insert(*name);
+                    changed = true;
+                }
+            }
+        }
+        for (name, pat) in self.grammar.iter() {
+            each_loop(pat, &empty, |p| {
+                errs.push(GrammarError {rule: name.to_owned(), msg: format!("{:s} can match without consuming input, so repeating it never ends", p.to_str())});
+            });
+        }
+        errs
+    }
+}
+
+impl<'self, T:'static+Clone+TokenCreator+Relocate> ParseContext<'self, T> {
+    // Replaces the text from start to end of old, the input last parsed,
+    // and returns the new input. What the memo table holds for the old input
+    // is kept where the edit can't have changed it, with the spans after it
+    // moved along, so parsing the new input only redoes the rules that looked
+    // at the edited part. Failures are dropped, since how they're reported
+    // depends on everything else that was tried.
+    pub fn edit(&self, old: &str, start: uint, end: uint, replacement: &str) -> ~str {
+        let new = old.slice_to(start) + replacement + old.slice_from(end);
+        let memo = match self.memo {
+            Some(memo) => memo,
+            None => return new
+        };
+        let delta = replacement.len() as int - (end - start) as int;
+        let moved = |i: uint| (i as int + delta) as uint;
+        let kept = {
+            // the line and columns of a span that moved are found again in the
+            // new input, since which of them changed depends on the lines its
+            // ends are on
+            let index = LineIndex::new(new);
+            let shift: &fn(&LineInfo) -> LineInfo = |l| index.info(new, moved(l.startslice), moved(l.endslice));
+            let mut kept = ~[];
+            for (&(ref name, pos), m) in memo.iter() {
+                match m.res {
+                    Ok(_) if m.reach <= start => kept.push(((name.clone(), pos), m.clone())),
+                    Ok(ref x) if pos >= end => kept.push(((name.clone(), moved(pos)), Memo {
+                        res: Ok(x.relocate(|l| shift(l))),
+                        reach: moved(m.reach),
+                        errors: m.errors.iter().map(|e| SyntaxError {pats: e.pats.clone(), instead: e.instead.clone(), user_msg: e.user_msg.clone(), line: shift(&e.line), is_malformed: e.is_malformed}).collect(),
+                        farthest: Farthest {pos: moved(m.farthest.pos), pats: m.farthest.pats.clone()}
+                    })),
+                    _ => ()
+                }
+            }
+            kept
+        };
+        memo.clear();
+        for (key, val) in kept.move_iter() {
+            memo.insert(key, val);
+        }
+        new
+    }
+}
+
+pub struct GrammarError {
+    rule: ~str,
+    msg: ~str
+}
+
+impl ToStr for GrammarError {
+    fn to_str(&self) -> ~str {
+        format!("{:s}: {:s}", self.rule, self.msg)
+    }
+}
+
+fn children<'a, 'b, T>(pat: &'b Pattern<'a, T>) -> ~[&'b Pattern<'a, T>] {
+    match *pat {
+        More(ref p) | MoreThan(_, ref p) | Exactly(_, ref p) | LessThan(_, ref p) |
+        And(ref p) | Not(ref p) | Predicate(ref p, _) | Capture(_, ref p) | Build(ref p, _) | Map(ref p, _) => ~[&**p],
+        Diff(ref a, ref b) | Recover(ref a, ref b) => ~[&**a, &**b],
+        Seq(ref arr) | Or(ref arr) => arr.iter().collect(),
+        Precedence(ref p, ref ops) => {
+            let mut arr = ~[&**p];
+            for op in ops.iter() {
+                arr.push(&op.pat);
+            }
+            arr
+        }
+        _ => ~[]
+    }
+}
+
+// Calls f with the name of every rule the pattern refers to.
+pub fn each_rule<'a, T>(pat: &Pattern<'a, T>, f: &fn(&'a str)) {
+    match *pat {
+        Rule(name) => f(name),
+        _ => for p in children(pat).iter() {
+            each_rule(*p, |r| f(r))
+        }
+    }
+}
+
+// Whether the pattern can succeed without consuming anything, given the
+// rules already known to. Match patterns are assumed to consume input.
+fn nullable<'a, T>(pat: &Pattern<'a, T>, empty: &HashSet<&'a str>) -> bool {
+    match *pat {
+        Rule(name) => empty.contains(&name),
+        Literal(s) => s.len() == 0,
+        Range(_, _) | Set(_) | Class(_) | Match(_) => false,
+        Chars(n) => n == 0,
+        More(_) | LessThan(_, _) | And(_) | Not(_) | Eof | Always(_) | Backref(_) => true,
+        MoreThan(n, ref p) | Exactly(n, ref p) => n == 0 || nullable(*p, empty),
+        Seq(ref arr) => arr.iter().all(|p| nullable(p, empty)),
+        Precedence(ref p, _) => nullable(*p, empty),
+        Or(ref arr) => arr.iter().any(|p| nullable(p, empty)),
+        Diff(ref p, _) | Recover(ref p, _) | Predicate(ref p, _) | Capture(_, ref p) | Build(ref p, _) | Map(ref p, _) => nullable(*p, empty)
+    }
+}
+
+// Calls f with every unbounded repetition of a pattern that can match
+// without consuming anything.
+fn each_loop<'a, T:Clone>(pat: &Pattern<'a, T>, empty: &HashSet<&'a str>, f: &fn(&Pattern<'a, T>)) {
+    match *pat {
+        More(ref p) | MoreThan(_, ref p) if nullable(*p, empty) => f(pat),
+        Precedence(ref p, ref ops) if nullable(*p, empty) && ops.iter().any(|x| nullable(&x.pat, empty)) => f(pat),
+        _ => ()
+    }
+    for p in children(pat).iter() {
+        each_loop(*p, empty, |x| f(x))
+    }
+}
+
+fn json_str(s: &str) -> ~str {
+    let mut out = ~"\"";
+    for c in s.iter() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if (c as uint) < 32 => {
+                out.push_str("\\u");
+                out.push_str(format!("{:04x}", c as uint));
+            }
+            c => out.push_char(c)
+        }
+    }
+    out.push_char('"');
+    out
+}
+
+// How much of text a failed comparison with s looked at: up to and including
+// the first byte that differs, which is past the end of text if all of it
+// matched.
+fn compared(s: &str, text: &str) -> uint {
+    let mut i = 0;
+    while i < s.len() && i < text.len() && s[i] == text[i] {
+        i += 1;
+    }
+    std::uint::min(i + 1, s.len())
+}
+
+// Undoes the captures made since the bindings list was mark long.
+fn unbind<'a, T>(ctx: &ParseContext<'a, T>, mark: uint) {
+    while ctx.bound.len() > mark {
+        match ctx.bound.pop() {
+            (name, Some(old)) => {
+                ctx.variables.insert(name, old);
+            }
+            (name, None) => {
+                ctx.variables.remove(&name);
+            }
+        }
+    }
+}
+
+fn trace_enter<'a, T>(ctx: &ParseContext<'a, T>, name: &str, position: uint) {
+    match ctx.trace {
+        Some(t) => t.push(Entered(name.to_owned(), position)),
+        None => ()
+    }
 }
 
-pub fn parse<'a,'b, T:'static+Clone+TokenCreator>(ctx: &'a ParseContext<'a, T>, pat: &'a Pattern<'a, T>, text: &str, position: uint) -> Result<Token<T>, SyntaxError> {
+fn trace_exit<'a, T>(ctx: &ParseContext<'a, T>, name: &str, position: uint, res: &Result<Token<T>, SyntaxError>) {
+    match ctx.trace {
+        Some(t) => t.push(match *res {
+            Ok(ref x) => Matched(name.to_owned(), position, x.line.endslice, format!("{:?}", x.value)),
+            Err(ref e) => Failed(name.to_owned(), position, e.message())
+        }),
+        None => ()
+    }
+}
+
+fn expected<'a, T>(ctx: &ParseContext<'a, T>, pos: uint, what: ~str) {
+    ctx.farthest.note(pos, what);
+}
+
+// Turns a failure into one listing everything that was expected at the
+// farthest point the parser reached, unless it has its own message.
+fn farthest<'a, T>(ctx: &ParseContext<'a, T>, e: SyntaxError, input: &str) -> SyntaxError {
+    let f = ctx.farthest;
+    if e.user_msg.is_some() || f.pats.len() == 0 || f.pos < e.line.startslice {
+        return e
+    }
+    let instead = if f.pos < input.len() {
+        format!("\"{:s}\"", from_char(input.char_at(f.pos)).escape_default())
+    } else {
+        ~"EOF"
+    };
+    SyntaxError {pats: f.pats.clone(), instead: Some(instead), user_msg: None, line: ctx.line_info(input, f.pos, f.pos + 1), is_malformed: e.is_malformed}
+}
+
+// Matches pat against input at position, a byte offset into it. Spans in the
+// result are offsets into the whole of input. This used to be parse, which
+// took the input from position on instead; it was renamed so that callers
+// still slicing the input fail to build rather than getting wrong spans.
+pub fn parse_at<'a, T:'static+Clone+TokenCreator>(ctx: &'a ParseContext<'a, T>, pat: &'a Pattern<'a, T>, input: &str, position: uint) -> Result<Token<T>, SyntaxError> {
+    // nothing a pattern captured outlives it failing
+    let mark = ctx.bound.len();
+    let res = parse_pattern(ctx, pat, input, position);
+    if res.is_err() {
+        unbind(ctx, mark);
+    }
+    res
+}
+
+fn parse_pattern<'a, T:'static+Clone+TokenCreator>(ctx: &'a ParseContext<'a, T>, pat: &'a Pattern<'a, T>, input: &str, position: uint) -> Result<Token<T>, SyntaxError> {
+    let text = input.slice_from(position);
     let tok: &fn(uint, uint) -> Result<Token<T>, SyntaxError> = |start, end| {
-        Ok(Token {value: TokenCreator::raw(text.slice(start, end).to_owned()), line: LineInfo::new(text, start+position, end+position)})
+        ctx.looked_at(end+position);
+        Ok(Token {value: TokenCreator::raw(text.slice(start, end).to_owned()), line: ctx.line_info(input, start+position, end+position)})
     };
     let seq: &fn(~[Token<T>], uint, uint) -> Result<Token<T>, SyntaxError> = |children, start, end| {
-        Ok(Token {value: TokenCreator::sequence(children), line: LineInfo::new(text, start+position, end+position)})
+        Ok(Token {value: TokenCreator::sequence(children), line: ctx.line_info(input, start+position, end+position)})
     };
     let err = |name, instead, start:uint, end:uint, is_malformed| {
-        Err(SyntaxError {pats: ~[name], instead: instead, user_msg: None, line: LineInfo::new(text, start+position, end+position), is_malformed: is_malformed})
+        Err(SyntaxError {pats: ~[name], instead: instead, user_msg: None, line: ctx.line_info(input, start+position, end+position), is_malformed: is_malformed})
+    };
+    // primitive patterns failing also note what they wanted
+    let miss = |name: ~str, instead, start:uint, end:uint| {
+        ctx.looked_at(end+position);
+        expected(ctx, position, name.clone());
+        err(name, instead, start, end, false)
     };
     match *pat {
-        Rule(name) => parse(ctx, ctx.grammar.get(&name), text, position),
+        Rule(name) => {
+            let rule = match ctx.grammar.find(&name) {
+                Some(p) => p,
+                None => return Err(SyntaxError {pats: ~[name.to_owned()], instead: None, user_msg: Some(~"No such rule"), line: ctx.line_info(input, position, position), is_malformed: true})
+            };
+            let key = (name.to_owned(), position);
+            match ctx.memo {
+                Some(memo) => match memo.find(&key) {
+                    Some(m) => {
+                        ctx.looked_at(m.reach);
+                        ctx.errors.push_all(*m.errors);
+                        ctx.farthest.merge(&m.farthest);
+                        trace_enter(ctx, name, position);
+                        trace_exit(ctx, name, position, &m.res);
+                        return m.res.clone()
+                    }
+                    None => ()
+                },
+                None => ()
+            }
+            // Reaching a rule again without having consumed anything means
+            // it's left recursive. The inner call gets the result of the
+            // last round, starting with a failure, and the outer one keeps
+            // reparsing until the match stops getting longer.
+            match ctx.active.find_mut(&key) {
+                Some(&(ref res, ref mut recursed)) => {
+                    *recursed = true;
+                    trace_enter(ctx, name, position);
+                    trace_exit(ctx, name, position, res);
+                    return res.clone()
+                }
+                None => ()
+            }
+            ctx.active.insert(key.clone(), (err(name.to_owned(), None, 0, 0, false), false));
+            // what the rule expects is gathered on its own, so that it can
+            // be memoized along with the errors it skips
+            let before = (*ctx.farthest).clone();
+            *ctx.farthest = Farthest {pos: 0, pats: ~[]};
+            let errs = ctx.errors.len();
+            let outer = *ctx.reach;
+            *ctx.reach = position;
+            let mark = ctx.bound.len();
+            let outer_depends = *ctx.depends;
+            *ctx.depends = std::uint::max_value;
+            trace_enter(ctx, name, position);
+            let mut res = parse_at(ctx, rule, input, position);
+            loop {
+                match ctx.active.find(&key) {
+                    Some(&(_, true)) => (),
+                    _ => break
+                }
+                let end = match res {
+                    Ok(ref x) => x.line.endslice,
+                    Err(_) => break
+                };
+                ctx.active.insert(key.clone(), (res.clone(), true));
+                unbind(ctx, mark);
+                match parse_at(ctx, rule, input, position) {
+                    Ok(x) => if x.line.endslice > end {
+                        res = Ok(x)
+                    } else {
+                        break
+                    },
+                    Err(_) => break
+                }
+            }
+            // a rule that fails where it starts is reported by its name
+            // rather than by whatever it was made of
+            if res.is_err() && ctx.farthest.pos <= position {
+                *ctx.farthest = Farthest {pos: position, pats: ~[name.to_owned()]};
+            }
+            let effects = (*ctx.farthest).clone();
+            *ctx.farthest = before;
+            ctx.farthest.merge(&effects);
+            ctx.active.remove(&key);
+            // anything parsed here while a left recursive rule is still
+            // growing may have seen an unfinished result
+            let growing = ctx.active.iter().any(|(&(_, p), &(_, recursed))| p == position && recursed);
+            unbind(ctx, mark);
+            // nor can anything that used a capture from outside the rule,
+            // since it would match differently somewhere else
+            let dependent = *ctx.depends < mark;
+            *ctx.depends = std::uint::min(outer_depends, *ctx.depends);
+            match ctx.memo {
+                Some(memo) if !growing && !dependent => {
+                    memo.insert(key, Memo {res: res.clone(), reach: *ctx.reach, errors: ctx.errors.slice_from(errs).to_owned(), farthest: effects});
+                }
+                _ => ()
+            }
+            ctx.looked_at(outer);
+            trace_exit(ctx, name, position, &res);
+            res
+        }
         Literal(s) => {
-            if text.len() >= s.len() && text.slice_chars(0, s.char_len()) == s {
+            if text.starts_with(s) {
                 tok(0, s.len())
             } else {
-                err(s.to_owned(), None, 0, s.len(), false)
+                miss(format!("\"{:s}\"", s.escape_default()), None, 0, compared(s, text))
             }
         }
         Range(x, y) => {
-            if text.char_len() < 1 {
-                return err(format!("Character between {:c} and {:c}", x, y), Some(~"EOF"), 0, 1, false)
+            if text.len() < 1 {
+                return miss(format!("character between {:c} and {:c}", x, y), Some(~"EOF"), 0, 1)
             }
             let CharRange{ch, next} = text.char_range_at(0);
             if ch <= y && ch >= x {
                 tok(0, next)
             } else {
-                err(format!("Character between {:c} and {:c}", x, y), None, 0, 1, false)
+                miss(format!("character between {:c} and {:c}", x, y), None, 0, 1)
             }
         }
         Chars(n) => {
-            if text.char_len() >= n {
-                tok(0, text.slice_chars(0, n).len())
+            let mut end = 0;
+            for _ in range(0, n) {
+                if end >= text.len() {
+                    ctx.looked_at(input.len() + 1);
+                    return miss(format!("{:u} characters", n), Some(~"EOF"), 0, n)
+                }
+                end = text.char_range_at(end).next;
+            }
+            tok(0, end)
+        }
+        Class(class) => {
+            if text.len() < 1 {
+                return miss(class.to_str(), Some(~"EOF"), 0, 1)
+            }
+            let CharRange{ch, next} = text.char_range_at(0);
+            if class.contains(ch) {
+                tok(0, next)
             } else {
-                err(format!("{:u} characters", n), Some(~"EOF"), 0, n, false)
+                miss(class.to_str(), None, 0, 1)
             }
         }
         Set(ref arr) => {
-            if text.char_len() < 1 {
-                return err(format!("One of {:?}", from_chars(*arr)), Some(~"EOF"), 0, 1, false)
+            if text.len() < 1 {
+                return miss(format!("one of {:?}", from_chars(*arr)), Some(~"EOF"), 0, 1)
             }
             let CharRange{ch, next} = text.char_range_at(0);
             for elem in arr.iter() {
@@ -236,13 +953,13 @@ pub fn parse<'a,'b, T:'static+Clone+TokenCreator>(ctx: &'a ParseContext<'a, T>,
                     return tok(0, next)
                 }
             }
-            err(format!("One of {:?}", from_chars(*arr)), None, 0, 1, false)
+            miss(format!("one of {:?}", from_chars(*arr)), None, 0, 1)
         }
         More(ref p) => {
             let mut acc = 0;
             let mut res = ~[];
             while acc <= text.len() {
-                match parse(ctx, *p, text.slice_from(acc), acc + position) {
+                match parse_at(ctx, *p, input, position + acc) {
                     Ok(x) => {
                         acc = x.line.endslice - position;
                         res.push(x);
@@ -260,7 +977,7 @@ pub fn parse<'a,'b, T:'static+Clone+TokenCreator>(ctx: &'a ParseContext<'a, T>,
             let mut acc = 0;
             let mut res = ~[];
             for _ in range(0, n) {
-                match parse(ctx, *p, text.slice_from(acc), acc + position) {
+                match parse_at(ctx, *p, input, position + acc) {
                     Ok(x) => {
                         acc = x.line.endslice - position;
                         res.push(x);
@@ -269,7 +986,7 @@ pub fn parse<'a,'b, T:'static+Clone+TokenCreator>(ctx: &'a ParseContext<'a, T>,
                 }
             }
             while acc <= text.len() {
-                match parse(ctx, *p, text.slice_from(acc), acc + position) {
+                match parse_at(ctx, *p, input, position + acc) {
                     Ok(x) => {
                         acc = x.line.endslice - position;
                         res.push(x);
@@ -287,7 +1004,7 @@ pub fn parse<'a,'b, T:'static+Clone+TokenCreator>(ctx: &'a ParseContext<'a, T>,
             let mut acc = 0;
             let mut res = ~[];
             for _ in range(0, n) {
-                match parse(ctx, *p, text.slice_from(acc), acc + position) {
+                match parse_at(ctx, *p, input, position + acc) {
                     Ok(x) => {
                         acc = x.line.endslice - position;
                         res.push(x);
@@ -301,7 +1018,7 @@ pub fn parse<'a,'b, T:'static+Clone+TokenCreator>(ctx: &'a ParseContext<'a, T>,
             let mut acc = 0;
             let mut res = ~[];
             for _ in range(0, n) {
-                match parse(ctx, *p, text.slice_from(acc), acc + position) {
+                match parse_at(ctx, *p, input, position + acc) {
                     Ok(x) => {
                         acc = x.line.endslice - position;
                         res.push(x);
@@ -317,7 +1034,7 @@ pub fn parse<'a,'b, T:'static+Clone+TokenCreator>(ctx: &'a ParseContext<'a, T>,
             let mut acc = 0;
             let mut res = ~[];
             for elem in arr.iter() {
-                match parse(ctx, elem, text.slice_from(acc), position + acc) {
+                match parse_at(ctx, elem, input, position + acc) {
                     Ok(x) => {
                         acc = x.line.endslice - position;
                         res.push(x);
@@ -334,11 +1051,13 @@ pub fn parse<'a,'b, T:'static+Clone+TokenCreator>(ctx: &'a ParseContext<'a, T>,
         Or(ref arr) => {
             let mut malformed = None;
             for elem in arr.iter() {
-                match parse(ctx, elem, text, position) {
+                match parse_at(ctx, elem, input, position) {
                     Ok(x) => return Ok(x),
-                    Err(x) => if x.is_malformed && malformed.is_none() {
-                        malformed = Some(x)
-                    },
+                    Err(x) => {
+                        if x.is_malformed && malformed.is_none() {
+                            malformed = Some(x)
+                        }
+                    }
                 }
             }
             match malformed {
@@ -346,35 +1065,113 @@ pub fn parse<'a,'b, T:'static+Clone+TokenCreator>(ctx: &'a ParseContext<'a, T>,
                 None => err(pat.to_str(), None, 0, text.len(), false)
             }
         }
-        Diff(ref a, ref b) => match parse(ctx, *b, text, position) {
+        Precedence(ref p, ref ops) => climb(ctx, *p, *ops, input, position, 0),
+        Diff(ref a, ref b) => match parse_at(ctx, *b, input, position) {
             Ok(_) => err(format!("Not {:?}",b), None, 0, text.len(), false),
-            Err(_) => parse(ctx, *a, text, position)
+            Err(_) => parse_at(ctx, *a, input, position)
+        },
+        And(ref p) => match parse_at(ctx, *p, input, position) {
+            Ok(x) => Ok(Token {value: x.value, line: ctx.line_info(input, position, position)}),
+            Err(x) => Err(x)
+        },
+        Not(ref p) => match parse_at(ctx, *p, input, position) {
+            Ok(_) => err(pat.to_str(), None, 0, 0, false),
+            Err(_) => seq(~[], 0, 0)
         },
-        And(ref p) => match parse(ctx, *p, text, position) {
-            Ok(x) => Ok(Token {value: x.value, line: LineInfo::new(text, position, position)}),
+        Eof => if text.len() == 0 {
+            ctx.looked_at(position + 1);
+            seq(~[], 0, 0)
+        } else {
+            miss(~"end of input", None, 0, 1)
+        },
+        Always(ref v) => Ok(Token {value: v.clone(), line: ctx.line_info(input, position, position)}),
+        Predicate(ref p, ref f) => match parse_at(ctx, *p, input, position) {
+            Ok(x) => if (*f)(&x) {
+                Ok(x)
+            } else {
+                err(pat.to_str(), None, x.line.startslice - position, x.line.endslice - position, false)
+            },
             Err(x) => Err(x)
         },
-        Always(ref v) => Ok(Token {value: v.clone(), line: LineInfo::new(text, position, position)}),
-        /*Match(ref f) => match (*f)(ctx, text) {
-            Ok((v,n)) => Ok(Token {value: v, line: LineInfo::new(position, position+n)}),
-            Err(s) => Err(SyntaxError {pats: ~[pat.to_str()], instead: None, user_msg: Some(s), line: LineInfo::new(position, position+1), is_malformed: false})
-        },*/
-        Build(ref p, ref f) => match parse(ctx, *p, text, position) {
-            Ok(x) => match (*f)(text.slice(x.line.startslice-position, x.line.endslice-position).to_owned()) {
+        Capture(name, ref p) => match parse_at(ctx, *p, input, position) {
+            Ok(x) => {
+                let old = ctx.variables.pop(&name.to_owned());
+                ctx.bound.push((name.to_owned(), old));
+                ctx.variables.insert(name.to_owned(), x.clone());
+                Ok(x)
+            }
+            Err(e) => Err(e)
+        },
+        Backref(name) => {
+            let (start, end) = match ctx.variables.find(&name.to_owned()) {
+                Some(x) => (x.line.startslice, x.line.endslice),
+                // nothing captured yet, which is no worse than having
+                // captured something that isn't here
+                None => return miss(pat.to_str(), None, 0, 0)
+            };
+            // the rules this is in can only be memoized if the capture
+            // was made inside them
+            let mut i = ctx.bound.len();
+            while i > 0 {
+                i -= 1;
+                match ctx.bound[i] {
+                    (ref n, _) if n.as_slice() == name => {
+                        *ctx.depends = std::uint::min(i, *ctx.depends);
+                        break
+                    }
+                    _ => ()
+                }
+            }
+            let s = input.slice(start, end);
+            if text.starts_with(s) {
+                tok(0, s.len())
+            } else {
+                miss(format!("\"{:s}\"", s.escape_default()), None, 0, compared(s, text))
+            }
+        }
+        Recover(ref p, ref sync) => {
+            *ctx.farthest = Farthest {pos: 0, pats: ~[]};
+            match parse_at(ctx, *p, input, position) {
+                Ok(x) => Ok(x),
+                Err(e) => {
+                    // at the end there's nothing to skip, and failing lets
+                    // repetitions around this stop
+                    if text.len() == 0 {
+                        return Err(e)
+                    }
+                    ctx.errors.push(farthest(ctx, e, input));
+                    let mut end = text.char_range_at(0).next;
+                    while end < text.len() && parse_at(ctx, *sync, input, position + end).is_err() {
+                        end = text.char_range_at(end).next;
+                    }
+                    tok(0, end)
+                }
+            }
+        }
+        Match(ref f) => {
+            let res = (*f)(ctx, input, position);
+            match res {
+                Ok(ref x) => ctx.looked_at(x.line.endslice),
+                Err(ref e) => ctx.looked_at(e.line.endslice)
+            }
+            res
+        }
+        Build(ref p, ref f) => match parse_at(ctx, *p, input, position) {
+            Ok(x) => match (**f)(text.slice(x.line.startslice-position, x.line.endslice-position).to_owned(), x.line.clone()) {
                 Ok(v) => {
                     Ok(Token {value: v, line: x.line})
                 }
-                Err(s) => {
-                    Err(SyntaxError {pats: ~[format!("{:?}", p)], instead: None, user_msg: Some(s), line: x.line, is_malformed: true})
+                Err(e) => {
+                    Err(SyntaxError {pats: ~[p.to_str()], instead: None, user_msg: Some(e.msg), line: e.line.unwrap_or(x.line), is_malformed: true})
                 }
             },
             Err(x) => Err(x)
         },
-        Map(ref p, ref f) => match parse(ctx, *p, text, position) {
-            Ok(x) => match (*f)(x.value.clone()) {
+        Map(ref p, ref f) => match parse_at(ctx, *p, input, position) {
+            Ok(x) => match (**f)(x.clone()) {
                 Ok(v) => Ok(Token {value: v, line: x.line}),
-                Err(s) => {
-                    Err(SyntaxError {pats: ~[format!("{:?}", p)], instead: None, user_msg: Some(s), line: x.line, is_malformed: true})
+                Err(e) => {
+                    Err(SyntaxError {pats: ~[p.to_str()], instead: None, user_msg: Some(e.msg), line: e.line.unwrap_or(x.line), is_malformed: true})
                 }
             },
             Err(x) => Err(x)
@@ -383,3 +1180,390 @@ pub fn parse<'a,'b, T:'static+Clone+TokenCreator>(ctx: &'a ParseContext<'a, T>,
     }
 }
 
+// Parses an operand and then any operators binding at least as tightly as
+// min, each with the operands to its right that bind tighter still (or as
+// tightly, for right associative ones). Once an operator has matched, the
+// operand after it has to.
+fn climb<'a, T:'static+Clone+TokenCreator>(ctx: &'a ParseContext<'a, T>, operand: &'a Pattern<'a, T>, ops: &'a [Operator<'a, T>], input: &str, position: uint, min: uint) -> Result<Token<T>, SyntaxError> {
+    let mut lhs = match parse_at(ctx, operand, input, position) {
+        Ok(x) => x,
+        Err(e) => return Err(e)
+    };
+    loop {
+        let mut found = None;
+        for op in ops.iter() {
+            if op.prec < min {
+                continue
+            }
+            match parse_at(ctx, &op.pat, input, lhs.line.endslice) {
+                Ok(x) => {
+                    found = Some((op, x));
+                    break
+                }
+                Err(e) => if e.is_malformed {
+                    return Err(e)
+                }
+            }
+        }
+        let (op, optok) = match found {
+            Some(x) => x,
+            None => return Ok(lhs)
+        };
+        let rhs = match op.assoc {
+            // nothing binds tighter than the tightest there can be
+            Left if op.prec == std::uint::max_value => parse_at(ctx, operand, input, optok.line.endslice),
+            Left => climb(ctx, operand, ops, input, optok.line.endslice, op.prec + 1),
+            Right => climb(ctx, operand, ops, input, optok.line.endslice, op.prec)
+        };
+        match rhs {
+            Ok(rhs) => {
+                let line = ctx.line_info(input, lhs.line.startslice, rhs.line.endslice);
+                lhs = Token {value: TokenCreator::sequence(~[lhs, optok, rhs]), line: line};
+            }
+            Err(e) => return Err(SyntaxError {pats: e.pats.clone(), instead: e.instead.clone(), user_msg: e.user_msg.clone(), line: e.line.clone(), is_malformed: true})
+        }
+    }
+}
+
+// Parses the whole of text, carrying on past errors wherever the grammar has
+// Recover patterns. Returns what could be parsed, which has the skipped text
+// in place of anything that failed, along with every error in order.
+pub fn parse_all<'a, T:'static+Clone+TokenCreator>(ctx: &'a ParseContext<'a, T>, pat: &'a Pattern<'a, T>, text: &str) -> (Option<Token<T>>, ~[SyntaxError]) {
+    ctx.errors.truncate(0);
+    *ctx.farthest = Farthest {pos: 0, pats: ~[]};
+    *ctx.lines = Some(LineIndex::new(text));
+    let res = parse_at(ctx, pat, text, 0);
+    let mut errors = (*ctx.errors).clone();
+    ctx.errors.truncate(0);
+    let res = match res {
+        Ok(x) => (Some(x), errors),
+        Err(e) => {
+            errors.push(farthest(ctx, e, text));
+            (None, errors)
+        }
+    };
+    *ctx.lines = None;
+    res
+}
+
+// Parses items one after another from a reader, handing each over as soon as
+// it's finished, so input can be acted on while more is still coming. Only the
+// text from the end of the last item on is kept, and it grows a line at a time
+// for as long as a parse looks past the end of it. Spans are into the whole
+// input, as if it had been parsed at once.
+pub struct Stream<'a, T> {
+    ctx: &'a ParseContext<'a, T>,
+    // one item, what goes between items, and where to carry on from when an
+    // item fails to parse
+    item: &'a Pattern<'a, T>,
+    skip: &'a Pattern<'a, T>,
+    sync: &'a Pattern<'a, T>,
+    reader: @Reader,
+    window: ~str,
+    // where the window starts in the whole input
+    offset: uint,
+    line: int,
+    col: uint,
+    eof: bool
+}
+
+impl<'a, T:'static+Clone+TokenCreator+Relocate> Stream<'a, T> {
+    pub fn new(ctx: &'a ParseContext<'a, T>, item: &'a Pattern<'a, T>, skip: &'a Pattern<'a, T>, sync: &'a Pattern<'a, T>, reader: @Reader) -> Stream<'a, T> {
+        Stream {ctx: ctx, item: item, skip: skip, sync: sync, reader: reader, window: ~"", offset: 0, line: 0, col: 0, eof: false}
+    }
+    // Adds the next line to the window. Lines are read with their newline
+    // put back, even the last, where it makes no difference.
+    fn fill(&mut self) {
+        if self.reader.eof() {
+            self.eof = true;
+            return
+        }
+        let line = self.reader.read_line();
+        if line.len() == 0 && self.reader.eof() {
+            self.eof = true;
+            return
+        }
+        let len = self.window.len();
+        let window = self.ctx.edit(self.window, len, len, line + "\n");
+        self.window = window;
+    }
+    // Drops the first end bytes of the window.
+    fn consume(&mut self, end: uint) {
+        {
+            let gone = self.window.slice_to(end);
+            for c in gone.iter() {
+                if c == '\n' {
+                    self.line += 1;
+                }
+            }
+            self.col = match gone.rfind('\n') {
+                Some(i) => gone.slice_from(i + 1).char_len(),
+                None => self.col + gone.char_len()
+            };
+        }
+        self.offset += end;
+        let window = self.ctx.edit(self.window, 0, end, "");
+        self.window = window;
+    }
+    // Parses pat at the start of the window, reading more for as long as
+    // that could change the result.
+    fn attempt(&mut self, pat: &'a Pattern<'a, T>) -> Result<Token<T>, SyntaxError> {
+        loop {
+            *self.ctx.reach = 0;
+            *self.ctx.farthest = Farthest {pos: 0, pats: ~[]};
+            *self.ctx.lines = Some(LineIndex::new(self.window));
+            let res = parse_at(self.ctx, pat, self.window, 0);
+            *self.ctx.lines = None;
+            if self.eof || *self.ctx.reach <= self.window.len() {
+                return res
+            }
+            self.fill();
+        }
+    }
+    // Moves a span in the window to where it is in the whole input.
+    fn place(&self, l: &LineInfo) -> LineInfo {
+        let col = if l.line == 0 { self.col } else { 0 };
+        LineInfo {line: l.line + self.line, startcol: l.startcol + col, endcol: l.endcol + col, startslice: l.startslice + self.offset, endslice: l.endslice + self.offset}
+    }
+}
+
+impl<'a, T:'static+Clone+TokenCreator+Relocate> Iterator<Result<Token<T>, SyntaxError>> for Stream<'a, T> {
+    fn next(&mut self) -> Option<Result<Token<T>, SyntaxError>> {
+        loop {
+            match self.attempt(self.skip) {
+                Ok(x) => self.consume(x.line.endslice),
+                Err(_) => ()
+            }
+            if self.window.len() > 0 {
+                break
+            }
+            if self.eof {
+                return None
+            }
+            self.fill();
+        }
+        match self.attempt(self.item) {
+            Ok(x) => {
+                let tok = x.relocate(|l| self.place(l));
+                self.consume(x.line.endslice);
+                Some(Ok(tok))
+            }
+            Err(e) => {
+                let e = farthest(self.ctx, e, self.window);
+                let e = SyntaxError {pats: e.pats.clone(), instead: e.instead.clone(), user_msg: e.user_msg.clone(), line: self.place(&e.line), is_malformed: e.is_malformed};
+                // skip at least a character, as Recover does
+                loop {
+                    if self.window.len() == 0 {
+                        break
+                    }
+                    let next = self.window.char_range_at(0).next;
+                    self.consume(next);
+                    if self.window.len() == 0 || self.attempt(self.sync).is_ok() {
+                        break
+                    }
+                }
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use grammar::{FRToken, FRSeq, Unparsed};
+
+    // Parses text with a grammar that backtracks over a rule which skips
+    // errors and then tries it again at the same place, returning where the
+    // parse ended and every error reported.
+    fn recovering(memoize: bool, text: &str) -> (Option<uint>, ~[~str]) {
+        let mut ctx: ParseContext<FRToken> = ParseContext::new();
+        ctx.rule("top", ~Rule("items") * ~Literal("!") + ~Rule("items") * ~Literal("?"));
+        ctx.rule("items", ~More(~Diff(~Recover(~Rule("item"), ~And(~Literal("x"))), ~Literal("!") + ~Literal("?"))));
+        ctx.rule("item", ~Literal("x") * ~Literal(";"));
+        if memoize {
+            ctx.memoize();
+        }
+        let (res, errors) = parse_all(&ctx, ctx.grammar.get(&"top"), text);
+        (res.map(|x| x.line.endslice), errors.map(|e| e.to_str()))
+    }
+
+    // How much of text rule matches, if it does.
+    fn matched(ctx: &ParseContext<FRToken>, rule: &'static str, text: &str) -> Option<uint> {
+        match parse_at(ctx, ctx.grammar.get(&rule), text, 0) {
+            Ok(x) => Some(x.line.endslice),
+            Err(_) => None
+        }
+    }
+
+    fn never(_: &Token<FRToken>) -> bool {
+        false
+    }
+
+    // Refuses to add zero, pointing at the zero.
+    fn no_zero(tok: Token<FRToken>) -> Result<FRToken, ActionError> {
+        match tok.value {
+            FRSeq([_, _, Token {value: Unparsed(ref s), line: ref l}]) if s.as_slice() == "0" => Err(ActionError::at(*l, "Adding zero")),
+            v => Ok(v)
+        }
+    }
+
+    #[test]
+    fn backrefs_match_what_was_captured() {
+        let mut ctx: ParseContext<FRToken> = ParseContext::new();
+        ctx.rule("word", ~MoreThan(1, ~Range('A', 'Z')));
+        ctx.rule("heredoc", ~Literal("<<") * ~Capture("end", ~Rule("word")) * ~Literal("\n") *
+                            ~More(~Diff(~Chars(1), ~Literal("\n") * ~Backref("end"))) * ~Literal("\n") * ~Backref("end"));
+        ctx.rule("name", ~MoreThan(1, ~Range('a', 'z')));
+        ctx.rule("element", ~Diff(~Literal("<"), ~Literal("</")) * ~Capture("tag", ~Rule("name")) * ~Literal(">") *
+                            ~More(~Rule("element") + ~Rule("name")) * ~Literal("</") * ~Backref("tag") * ~Literal(">"));
+        assert_eq!(matched(&ctx, "heredoc", "<<EOF\nsome\nEOD\nEOF"), Some(18));
+        assert_eq!(matched(&ctx, "heredoc", "<<EOF\nsome\nEOD"), None);
+        assert_eq!(matched(&ctx, "element", "<a><b>x</b>y</a>"), Some(16));
+        assert_eq!(matched(&ctx, "element", "<a><b>x</a></b>"), None);
+    }
+
+    #[test]
+    fn failed_patterns_keep_nothing_they_captured() {
+        let mut ctx: ParseContext<FRToken> = ParseContext::new();
+        let after = || ~LessThan(1, ~Backref("x"));
+        ctx.rule("predicate", ~LessThan(1, ~Predicate(~Capture("x", ~Literal("a")), never)) * after());
+        ctx.rule("seq", ~LessThan(1, ~Predicate(~Capture("x", ~Literal("a")), never) * ~Literal("b")) * after());
+        ctx.rule("not", ~LessThan(1, ~Not(~Capture("x", ~Literal("a")))) * after());
+        ctx.rule("diff", ~LessThan(1, ~Diff(~Literal("b"), ~Capture("x", ~Literal("a")))) * after());
+        for rule in ["predicate", "seq", "not", "diff"].iter() {
+            assert_eq!(matched(&ctx, *rule, "a"), Some(0));
+        }
+    }
+
+    #[test]
+    fn memoizing_reports_the_same_errors() {
+        for text in ["x;x;?", "x;y;x;?", "x;y;x;.", "y;"].iter() {
+            assert_eq!(recovering(true, *text), recovering(false, *text));
+        }
+    }
+
+    // The token an operator expression made, with each operator's operands
+    // in parentheses.
+    fn nesting(tok: &Token<FRToken>) -> ~str {
+        match tok.value {
+            FRSeq(ref arr) => "(" + arr.map(|x| nesting(x)).concat() + ")",
+            Unparsed(ref s) => s.clone(),
+            _ => ~"?"
+        }
+    }
+
+    // How rule nests the whole of text, or why it doesn't.
+    fn nested(ctx: &ParseContext<FRToken>, rule: &'static str, text: &str) -> ~str {
+        ctx.forget();
+        match parse_at(ctx, ctx.grammar.get(&rule), text, 0) {
+            Ok(x) if x.line.endslice == text.len() => nesting(&x),
+            Ok(_) => ~"unfinished",
+            Err(e) => e.to_str()
+        }
+    }
+
+    fn operators(ops: ~[(&'static str, uint, Assoc)], text: &str) -> ~str {
+        let mut ctx: ParseContext<FRToken> = ParseContext::new();
+        ctx.rule("expr", ~Precedence(~Range('0', '9'), ops.move_iter().map(|(s, prec, assoc)| Operator {pat: Literal(s), prec: prec, assoc: assoc}).collect()));
+        nested(&ctx, "expr", text)
+    }
+
+    #[test]
+    fn operators_group_by_precedence_and_associativity() {
+        let arith = || ~[("-", 1, Left), ("*", 2, Left), ("^", 3, Right)];
+        assert_eq!(operators(arith(), "1-2-3"), ~"((1-2)-3)");
+        assert_eq!(operators(arith(), "2^3^2"), ~"(2^(3^2))");
+        assert_eq!(operators(arith(), "1-2*3^4^5*6-7"), ~"((1-((2*(3^(4^5)))*6))-7)");
+        assert_eq!(operators(~[("-", std::uint::max_value, Left)], "3-2-1"), ~"((3-2)-1)");
+    }
+
+    #[test]
+    fn left_recursion_grows_from_the_seed() {
+        for memoize in [false, true].iter() {
+            let mut ctx: ParseContext<FRToken> = ParseContext::new();
+            ctx.rule("num", ~Range('0', '9'));
+            ctx.rule("sum", ~Rule("sum") * ~Literal("-") * ~Rule("num") + ~Rule("num"));
+            // through another rule, and with the recursion one of two
+            ctx.rule("list", ~Rule("items") + ~Rule("num"));
+            ctx.rule("items", ~Rule("list") * ~Literal(",") * ~Rule("num"));
+            if *memoize {
+                ctx.memoize();
+            }
+            assert_eq!(nested(&ctx, "sum", "1"), ~"1");
+            assert_eq!(nested(&ctx, "sum", "1-2-3"), ~"((1-2)-3)");
+            assert_eq!(nested(&ctx, "list", "1,2,3"), ~"((1,2),3)");
+        }
+    }
+
+    #[test]
+    fn failures_list_everything_expected_where_parsing_got_furthest() {
+        let mut ctx: ParseContext<FRToken> = ParseContext::new();
+        ctx.rule("top", ~Rule("call") + ~Rule("num"));
+        ctx.rule("call", ~Rule("name") * ~Literal("(") * (~Rule("num") + ~Rule("name")) * ~Literal(")"));
+        ctx.rule("name", ~MoreThan(1, ~Range('a', 'z')));
+        ctx.rule("num", ~MoreThan(1, ~Range('0', '9')));
+        let fails = |text: &str| match parse_all(&ctx, ctx.grammar.get(& &"top"), text) {
+            (None, [ref e]) => e.message(),
+            _ => ~"parsed"
+        };
+        // the call got furthest, to where its argument should be, and the
+        // rules that failed right there are named instead of their parts
+        assert_eq!(fails("f(+"), ~"Expected num, or name, got \"+\"");
+        assert_eq!(fails("f(1"), ~"Expected character between 0 and 9, or \")\", got EOF");
+        assert_eq!(fails("+"), ~"Expected call, or num, got \"+\"");
+    }
+
+    #[test]
+    fn trace_shows_each_rule_tried_and_what_it_came_to() {
+        let mut ctx: ParseContext<FRToken> = ParseContext::new();
+        ctx.rule("top", ~Rule("pair"));
+        ctx.rule("pair", ~Rule("a") * ~Rule("b"));
+        ctx.rule("a", ~Literal("a"));
+        ctx.rule("b", ~Literal("b"));
+        ctx.trace();
+        parse_all(&ctx, ctx.grammar.get(& &"top"), "ax");
+        let log = ctx.trace_log();
+        let lines: ~[&str] = log.split_iter('\n').collect();
+        assert_eq!(lines.len(), 7);
+        assert_eq!(lines[0], "pair @0");
+        assert_eq!(lines[1], "  a @0");
+        assert!(lines[2].starts_with("  a @0-1 = "));
+        assert_eq!(lines[3], "  b @1");
+        assert_eq!(lines[4], "  b @1 failed: Expected \"b\"");
+        assert_eq!(lines[5], "pair @0 failed: Expected \"b\"");
+        assert!(ctx.trace_json().starts_with("[\n  {\"event\": \"enter\", \"rule\": \"pair\", \"pos\": 0},\n"));
+    }
+
+    #[test]
+    fn not_and_eof_look_ahead_without_consuming() {
+        let mut ctx: ParseContext<FRToken> = ParseContext::new();
+        ctx.rule("word", ~MoreThan(1, ~Range('a', 'z')) * ~Not(~Range('0', '9')));
+        ctx.rule("whole", ~Rule("word") * ~Eof);
+        assert_eq!(matched(&ctx, "word", "ab cd"), Some(2));
+        assert_eq!(matched(&ctx, "word", "ab1"), None);
+        assert_eq!(matched(&ctx, "whole", "ab"), Some(2));
+        assert_eq!(matched(&ctx, "whole", "ab "), None);
+    }
+
+    #[test]
+    fn action_errors_keep_their_span() {
+        let mut ctx: ParseContext<FRToken> = ParseContext::new();
+        ctx.rule("digit", ~Range('0', '9'));
+        ctx.rule("sum", ~Map(~Rule("digit") * ~Literal("+") * ~Rule("digit"), Mapper(no_zero)));
+        ctx.rule("num", ~Build(~Rule("digit"), Builder(|_, _| Err(ActionError::new("Not a number")))));
+        assert!(parse_at(&ctx, ctx.grammar.get(& &"sum"), "1+2", 0).is_ok());
+        match parse_at(&ctx, ctx.grammar.get(& &"sum"), "1+0", 0) {
+            Err(e) => {
+                assert_eq!(e.user_msg, Some(~"Adding zero"));
+                assert_eq!((e.line.startslice, e.line.endslice), (2, 3));
+            }
+            Ok(_) => fail!("zero was added")
+        }
+        // without a span, the error covers what the action was given
+        match parse_at(&ctx, ctx.grammar.get(& &"num"), "7", 0) {
+            Err(e) => assert_eq!((e.line.startslice, e.line.endslice), (0, 1)),
+            Ok(_) => fail!("the action didn't fail")
+        }
+    }
+}
@@ -0,0 +1,484 @@
+// Loads grammars written as text into a ParseContext, so they can be changed
+// without recompiling.
+//
+//     # comments run to the end of the line
+//     number  <- digit+ ("." digit+)? => make_number
+//     digit   <- [0-9]
+//     list    <- "(" ws (number ws)* ")" -> make_list
+//     ws      <- [ \t\n]*
+//
+// Alternatives are separated by `/` and tried in order. An item is a rule
+// name, a "literal" or 'literal', a character class like [a-z_], `.` for any
+// character, or a group in parentheses. `*`, `+`, `?` and `{n}` repeat the
+// item before them, `&` and `!` look ahead without consuming (so `!.` is the
+// end of input) and `a - b` matches a where b doesn't. `name:item` captures
+// what the item matched until the rule returns, and `$name` matches the same
+// text again, as in `"<<" end:word "\n" (!$end line)* $end`. `=> name`
+// builds a token from the text an alternative matched and `-> name` maps its
+// token, using the actions registered under that name.
+
+use std::hashmap::*;
+use std::str::*;
+use parse::*;
+
+pub struct Actions<T> {
+    builds: HashMap<~str, Builder<T>>,
+    maps: HashMap<~str, Mapper<T>>
+}
+
+impl<T> Actions<T> {
+    pub fn new() -> Actions<T> {
+        Actions {builds: HashMap::new(), maps: HashMap::new()}
+    }
+    pub fn build(&mut self, name: &str, f: @fn(~str, LineInfo) -> Result<T, ActionError>) {
+        self.builds.insert(name.to_owned(), Builder(f));
+    }
+    pub fn map(&mut self, name: &str, f: @fn(Token<T>) -> Result<T, ActionError>) {
+        self.maps.insert(name.to_owned(), Mapper(f));
+    }
+}
+
+struct Reader<'a, 'b, T> {
+    src: &'a str,
+    pos: uint,
+    actions: &'b Actions<T>
+}
+
+impl<'a, 'b, T:'static+Clone> Reader<'a, 'b, T> {
+    fn err<U>(&self, expected: &str) -> Result<U, SyntaxError> {
+        let instead = match self.peek() {
+            Some(c) => c.to_str(),
+            None => ~"EOF"
+        };
+        Err(SyntaxError {pats: ~[expected.to_owned()], instead: Some(instead), user_msg: None, line: LineInfo::new(self.src, self.pos, self.pos + 1), is_malformed: true})
+    }
+    fn peek(&self) -> Option<char> {
+        if self.pos < self.src.len() {
+            Some(self.src.char_at(self.pos))
+        } else {
+            None
+        }
+    }
+    fn bump(&mut self) -> char {
+        let CharRange{ch, next} = self.src.char_range_at(self.pos);
+        self.pos = next;
+        ch
+    }
+    fn looking_at(&self, s: &str) -> bool {
+        self.src.slice_from(self.pos).starts_with(s)
+    }
+    fn eat(&mut self, s: &str) -> bool {
+        if self.looking_at(s) {
+            self.pos += s.len();
+            true
+        } else {
+            false
+        }
+    }
+    fn skip_ws(&mut self) {
+        loop {
+            match self.peek() {
+                Some(' ') | Some('\t') | Some('\r') | Some('\n') => {
+                    self.bump();
+                }
+                Some('#') => while self.peek().is_some() && self.peek() != Some('\n') {
+                    self.bump();
+                },
+                _ => return
+            }
+        }
+    }
+    // Rule names may contain dashes between letters or digits, so `a->b`
+    // still reads as a map action.
+    fn ident(&mut self) -> Option<&'a str> {
+        let start = self.pos;
+        match self.peek() {
+            Some(c) if c.is_alphabetic() || c == '_' => {
+                self.bump();
+            }
+            _ => return None
+        }
+        loop {
+            match self.peek() {
+                Some(c) if c.is_alphanumeric() || c == '_' => {
+                    self.bump();
+                }
+                Some('-') if self.pos + 1 < self.src.len() && self.src.char_at(self.pos + 1).is_alphanumeric() => {
+                    self.bump();
+                }
+                _ => break
+            }
+        }
+        Some(self.src.slice(start, self.pos))
+    }
+    // Whether a new rule starts here, which ends the one before it.
+    fn at_rule(&mut self) -> bool {
+        let start = self.pos;
+        let res = self.ident().is_some() && {
+            self.skip_ws();
+            self.looking_at("<-")
+        };
+        self.pos = start;
+        res
+    }
+    fn action_name(&mut self) -> Result<~str, SyntaxError> {
+        self.skip_ws();
+        match self.ident() {
+            Some(name) => Ok(name.to_owned()),
+            None => self.err("action name")
+        }
+    }
+    fn choice(&mut self) -> Result<Pattern<'a, T>, SyntaxError> {
+        let mut alts = ~[];
+        loop {
+            match self.action() {
+                Ok(p) => alts.push(p),
+                Err(e) => return Err(e)
+            }
+            self.skip_ws();
+            if !self.eat("/") {
+                break
+            }
+        }
+        Ok(if alts.len() == 1 { alts.pop() } else { Or(alts) })
+    }
+    fn action(&mut self) -> Result<Pattern<'a, T>, SyntaxError> {
+        let seq = match self.sequence() {
+            Ok(p) => p,
+            Err(e) => return Err(e)
+        };
+        let start = self.pos;
+        if self.eat("=>") {
+            match self.action_name() {
+                Ok(name) => match self.actions.builds.find(&name) {
+                    Some(f) => Ok(Build(~seq, f.clone())),
+                    None => Err(SyntaxError {pats: ~[~"build action"], instead: Some(name.clone()), user_msg: None, line: LineInfo::new(self.src, start, self.pos), is_malformed: true})
+                },
+                Err(e) => Err(e)
+            }
+        } else if self.eat("->") {
+            match self.action_name() {
+                Ok(name) => match self.actions.maps.find(&name) {
+                    Some(f) => Ok(Map(~seq, f.clone())),
+                    None => Err(SyntaxError {pats: ~[~"map action"], instead: Some(name.clone()), user_msg: None, line: LineInfo::new(self.src, start, self.pos), is_malformed: true})
+                },
+                Err(e) => Err(e)
+            }
+        } else {
+            Ok(seq)
+        }
+    }
+    fn sequence(&mut self) -> Result<Pattern<'a, T>, SyntaxError> {
+        let mut items = ~[];
+        loop {
+            self.skip_ws();
+            match self.peek() {
+                None | Some('/') | Some(')') => break,
+                _ if self.looking_at("->") || self.looking_at("=>") || self.at_rule() => break,
+                _ => match self.prefixed() {
+                    Ok(p) => items.push(p),
+                    Err(e) => return Err(e)
+                }
+            }
+        }
+        match items.len() {
+            0 => self.err("pattern"),
+            1 => Ok(items.pop()),
+            _ => Ok(Seq(items))
+        }
+    }
+    fn prefixed(&mut self) -> Result<Pattern<'a, T>, SyntaxError> {
+        if self.eat("&") {
+            self.skip_ws();
+            match self.diff() {
+                Ok(p) => Ok(And(~p)),
+                Err(e) => Err(e)
+            }
+        } else if self.eat("!") {
+            self.skip_ws();
+            match self.diff() {
+                Ok(p) => Ok(Not(~p)),
+                Err(e) => Err(e)
+            }
+        } else {
+            self.diff()
+        }
+    }
+    fn diff(&mut self) -> Result<Pattern<'a, T>, SyntaxError> {
+        let a = match self.suffixed() {
+            Ok(p) => p,
+            Err(e) => return Err(e)
+        };
+        let start = self.pos;
+        self.skip_ws();
+        if self.looking_at("-") && !self.looking_at("->") {
+            self.bump();
+            self.skip_ws();
+            match self.suffixed() {
+                Ok(b) => Ok(Diff(~a, ~b)),
+                Err(e) => Err(e)
+            }
+        } else {
+            self.pos = start;
+            Ok(a)
+        }
+    }
+    fn suffixed(&mut self) -> Result<Pattern<'a, T>, SyntaxError> {
+        let p = match self.primary() {
+            Ok(p) => p,
+            Err(e) => return Err(e)
+        };
+        match self.peek() {
+            Some('*') => {
+                self.bump();
+                Ok(More(~p))
+            }
+            Some('+') => {
+                self.bump();
+                Ok(MoreThan(1, ~p))
+            }
+            Some('?') => {
+                self.bump();
+                Ok(LessThan(1, ~p))
+            }
+            Some('{') => {
+                self.bump();
+                let start = self.pos;
+                while self.peek().map_default(false, |c| c.is_digit()) {
+                    self.bump();
+                }
+                match from_str::<uint>(self.src.slice(start, self.pos)) {
+                    Some(n) => if self.eat("}") {
+                        Ok(Exactly(n, ~p))
+                    } else {
+                        self.err("}")
+                    },
+                    None => self.err("count")
+                }
+            }
+            _ => Ok(p)
+        }
+    }
+    fn primary(&mut self) -> Result<Pattern<'a, T>, SyntaxError> {
+        match self.peek() {
+            Some('"') | Some('\'') => self.literal(),
+            Some('[') => self.class(),
+            Some('.') => {
+                self.bump();
+                Ok(Chars(1))
+            }
+            Some('(') => {
+                self.bump();
+                self.skip_ws();
+                let p = match self.choice() {
+                    Ok(p) => p,
+                    Err(e) => return Err(e)
+                };
+                self.skip_ws();
+                if self.eat(")") {
+                    Ok(p)
+                } else {
+                    self.err(")")
+                }
+            }
+            Some('$') => {
+                self.bump();
+                match self.ident() {
+                    Some(name) => Ok(Backref(name)),
+                    None => self.err("capture name")
+                }
+            }
+            _ => match self.ident() {
+                Some(name) if self.eat(":") => match self.suffixed() {
+                    Ok(p) => Ok(Capture(name, ~p)),
+                    Err(e) => Err(e)
+                },
+                Some(name) => Ok(Rule(name)),
+                None => self.err("pattern")
+            }
+        }
+    }
+    // One character of a literal or class, after any escape.
+    fn character(&mut self) -> Result<char, SyntaxError> {
+        match self.peek() {
+            None => self.err("character"),
+            Some('\\') => {
+                self.bump();
+                match self.peek() {
+                    None => self.err("escape"),
+                    Some('n') => {
+                        self.bump();
+                        Ok('\n')
+                    }
+                    Some('t') => {
+                        self.bump();
+                        Ok('\t')
+                    }
+                    Some('r') => {
+                        self.bump();
+                        Ok('\r')
+                    }
+                    Some(_) => Ok(self.bump())
+                }
+            }
+            Some(_) => Ok(self.bump())
+        }
+    }
+    // Literals borrow their text from the grammar, so an escaped character
+    // is matched on its own between the unescaped runs.
+    fn literal(&mut self) -> Result<Pattern<'a, T>, SyntaxError> {
+        let quote = self.bump();
+        let mut pieces = ~[];
+        let mut start = self.pos;
+        loop {
+            match self.peek() {
+                None => return self.err(quote.to_str()),
+                Some(c) if c == quote => break,
+                Some('\\') => {
+                    if self.pos > start {
+                        pieces.push(Literal(self.src.slice(start, self.pos)));
+                    }
+                    match self.character() {
+                        Ok(c) => pieces.push(Set(~[c])),
+                        Err(e) => return Err(e)
+                    }
+                    start = self.pos;
+                }
+                Some(_) => {
+                    self.bump();
+                }
+            }
+        }
+        if self.pos > start || pieces.len() == 0 {
+            pieces.push(Literal(self.src.slice(start, self.pos)));
+        }
+        self.bump();
+        Ok(if pieces.len() == 1 { pieces.pop() } else { Seq(pieces) })
+    }
+    fn class(&mut self) -> Result<Pattern<'a, T>, SyntaxError> {
+        self.bump();
+        let mut chars = ~[];
+        let mut alts = ~[];
+        loop {
+            match self.peek() {
+                None => return self.err("]"),
+                Some(']') => {
+                    self.bump();
+                    break
+                }
+                _ => {
+                    let c = match self.character() {
+                        Ok(c) => c,
+                        Err(e) => return Err(e)
+                    };
+                    if self.looking_at("-") && !self.looking_at("-]") {
+                        self.bump();
+                        match self.character() {
+                            Ok(d) => alts.push(Range(c, d)),
+                            Err(e) => return Err(e)
+                        }
+                    } else {
+                        chars.push(c);
+                    }
+                }
+            }
+        }
+        if chars.len() > 0 {
+            alts.push(Set(chars));
+        }
+        match alts.len() {
+            0 => self.err("character"),
+            1 => Ok(alts.pop()),
+            _ => Ok(Or(alts))
+        }
+    }
+}
+
+// Adds the rules defined in source to the context. Rule names and literals
+// borrow from source, so it has to live as long as the context.
+pub fn load_grammar<'a, T:'static+Clone>(ctx: &mut ParseContext<'a, T>, source: &'a str, actions: &Actions<T>) -> Result<(), SyntaxError> {
+    let mut r = Reader {src: source, pos: 0, actions: actions};
+    loop {
+        r.skip_ws();
+        if r.peek().is_none() {
+            return Ok(())
+        }
+        let name = match r.ident() {
+            Some(name) => name,
+            None => return r.err("rule name")
+        };
+        r.skip_ws();
+        if !r.eat("<-") {
+            return r.err("<-")
+        }
+        r.skip_ws();
+        match r.choice() {
+            Ok(p) => ctx.rule(name, ~p),
+            Err(e) => return Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use parse::*;
+    use grammar::FRToken;
+    use super::*;
+
+    static GRAMMAR: &'static str = "
+# one rule for each kind of pattern
+seq    <- \"a\" 'b'
+alt    <- \"x\" / \"y\"
+star   <- \"a\"*
+plus   <- \"a\"+
+opt    <- \"a\"? \"b\"
+count  <- \"a\"{3}
+and    <- &\"a\" .
+not    <- !\"a\" .
+diff   <- [a-z] - \"q\"
+group  <- (\"a\" / \"b\") \"c\"
+end    <- \"a\" !.
+tag    <- \"<\" t:[a-z]+ \">\" $t
+class  <- [a-c_\\]]+
+esc    <- \"\\t\\\"\\n\" '\\''
+dashed-name <- \"a\"->keep
+";
+
+    fn actions() -> Actions<FRToken> {
+        let mut actions = Actions::new();
+        actions.map("keep", |t: Token<FRToken>| Ok(t.value));
+        actions
+    }
+
+    // Whether rule matches the whole of text.
+    fn matches(ctx: &ParseContext<FRToken>, rule: &'static str, text: &str) -> bool {
+        match parse_at(ctx, ctx.grammar.get(&rule), text, 0) {
+            Ok(x) => x.line.endslice == text.len(),
+            Err(_) => false
+        }
+    }
+
+    #[test]
+    fn operators_and_escapes_load() {
+        let mut ctx = ParseContext::new();
+        assert!(load_grammar(&mut ctx, GRAMMAR, &actions()).is_ok());
+        let yes = [("seq", "ab"), ("alt", "y"), ("star", ""), ("star", "aaa"), ("plus", "aa"), ("opt", "b"), ("opt", "ab"),
+                   ("count", "aaa"), ("and", "a"), ("not", "b"), ("diff", "p"), ("group", "bc"), ("end", "a"),
+                   ("tag", "<ab>ab"), ("class", "a_]c"), ("esc", "\t\"\n'"), ("dashed-name", "a")];
+        let no = [("seq", "a"), ("alt", "z"), ("plus", ""), ("count", "aa"), ("count", "aaaa"), ("and", "b"), ("not", "a"),
+                  ("diff", "q"), ("group", "c"), ("tag", "<ab>ba"), ("class", "d"), ("esc", "\\t")];
+        for &(rule, text) in yes.iter() {
+            assert!(matches(&ctx, rule, text), format!("{:s} should match {:?}", rule, text));
+        }
+        for &(rule, text) in no.iter() {
+            assert!(!matches(&ctx, rule, text), format!("{:s} shouldn't match {:?}", rule, text));
+        }
+    }
+
+    #[test]
+    fn unknown_actions_are_errors() {
+        let mut ctx: ParseContext<FRToken> = ParseContext::new();
+        assert!(load_grammar(&mut ctx, "x <- 'a' => nope", &actions()).is_err());
+        assert!(load_grammar(&mut ctx, "x <- 'a' -> nope", &actions()).is_err());
+    }
+}
@@ -1,27 +1,110 @@
 use context::*;
 use parse::*;
 use ast::*;
+use std::num::{CheckedAdd, CheckedSub, CheckedMul};
 
-fn add(_: &mut Context, args: ~[FRValue]) -> Result<~[FRValue], ~str> {
-    let mut sum = 0f32;
-    for v in args.iter() {
-        match *v {
-            Number(n) => sum += n,
-            _ => return Err(format!("WTF: Expected number, got {:?}, this should have been caught by the type checker", v))
-        }
+// Integer arithmetic fails rather than wrapping around when the result
+// doesn't fit in 64 bits.
+fn arith(args: &[FRValue], fi: &fn(&i64, &i64) -> Option<i64>, ff: &fn(f32, f32) -> f32) -> Result<~[FRValue], ~str> {
+    match (&args[0], &args[1]) {
+        (&Int(x), &Int(y)) => match fi(&x, &y) {
+            Some(n) => Ok(~[Int(n)]),
+            None => Err(format!("{:s} and {:s} overflow an int", x.to_str(), y.to_str()))
+        },
+        (&Number(x), &Number(y)) => Ok(~[Number(ff(x, y))]),
+        _ => Err(format!("WTF: Expected numbers of the same type, got {:?}, this should have been caught by the type checker", args))
     }
-    Ok(~[Number(sum)])
 }
 
-fn list(_: &mut Context, args: ~[FRValue]) -> Result<~[FRValue], ~str> {
-    Ok(~[List(args)])
+fn add(_: &mut Context, args: &[FRValue]) -> Result<~[FRValue], ~str> {
+    arith(args, |x, y| x.checked_add(y), |x, y| x + y)
 }
 
-fn FRtypeof(_: &mut Context, args: ~[FRValue]) -> Result<~[FRValue], ~str> {
+fn sub(_: &mut Context, args: &[FRValue]) -> Result<~[FRValue], ~str> {
+    arith(args, |x, y| x.checked_sub(y), |x, y| x - y)
+}
+
+fn mul(_: &mut Context, args: &[FRValue]) -> Result<~[FRValue], ~str> {
+    arith(args, |x, y| x.checked_mul(y), |x, y| x * y)
+}
+
+fn compare(args: &[FRValue], fi: &fn(i64, i64) -> bool, ff: &fn(f32, f32) -> bool) -> Result<~[FRValue], ~str> {
+    match (&args[0], &args[1]) {
+        (&Int(x), &Int(y)) => Ok(~[Bool(fi(x, y))]),
+        (&Number(x), &Number(y)) => Ok(~[Bool(ff(x, y))]),
+        _ => Err(format!("WTF: Expected numbers of the same type, got {:?}, this should have been caught by the type checker", args))
+    }
+}
+
+fn less(_: &mut Context, args: &[FRValue]) -> Result<~[FRValue], ~str> {
+    compare(args, |x, y| x < y, |x, y| x < y)
+}
+
+fn greater(_: &mut Context, args: &[FRValue]) -> Result<~[FRValue], ~str> {
+    compare(args, |x, y| x > y, |x, y| x > y)
+}
+
+fn equal(_: &mut Context, args: &[FRValue]) -> Result<~[FRValue], ~str> {
+    compare(args, |x, y| x == y, |x, y| x == y)
+}
+
+fn not(_: &mut Context, args: &[FRValue]) -> Result<~[FRValue], ~str> {
+    match args[0] {
+        Bool(b) => Ok(~[Bool(!b)]),
+        _ => Err(format!("WTF: Expected bool, got {:?}, this should have been caught by the type checker", args[0]))
+    }
+}
+
+fn to_int(_: &mut Context, args: &[FRValue]) -> Result<~[FRValue], ~str> {
+    match args[0] {
+        // 2^63 is the smallest float too big for an int, and an f32 holds
+        // it exactly
+        Number(n) if n >= -9223372036854775808f32 && n < 9223372036854775808f32 => Ok(~[Int(n as i64)]),
+        Number(n) => Err(format!("{:s} is out of range for an int", n.to_str())),
+        _ => Err(format!("WTF: Expected float, got {:?}, this should have been caught by the type checker", args[0]))
+    }
+}
+
+fn to_float(_: &mut Context, args: &[FRValue]) -> Result<~[FRValue], ~str> {
+    match args[0] {
+        Int(n) => Ok(~[Number(n as f32)]),
+        _ => Err(format!("WTF: Expected int, got {:?}, this should have been caught by the type checker", args[0]))
+    }
+}
+
+fn dup(_: &mut Context, args: &[FRValue]) -> Result<~[FRValue], ~str> {
+    Ok(~[args[0].clone(), args[0].clone()])
+}
+
+fn drop(_: &mut Context, _: &[FRValue]) -> Result<~[FRValue], ~str> {
+    Ok(~[])
+}
+
+fn swap(_: &mut Context, args: &[FRValue]) -> Result<~[FRValue], ~str> {
+    Ok(~[args[1].clone(), args[0].clone()])
+}
+
+fn over(_: &mut Context, args: &[FRValue]) -> Result<~[FRValue], ~str> {
+    Ok(~[args[0].clone(), args[1].clone(), args[0].clone()])
+}
+
+fn FRprint(_: &mut Context, args: &[FRValue]) -> Result<~[FRValue], ~str> {
+    match args[0] {
+        String(ref s) => println(*s),
+        ref v => println(v.to_str())
+    }
+    Ok(~[])
+}
+
+fn list(_: &mut Context, args: &[FRValue]) -> Result<~[FRValue], ~str> {
+    Ok(~[List(args.to_owned())])
+}
+
+fn FRtypeof(_: &mut Context, args: &[FRValue]) -> Result<~[FRValue], ~str> {
     Ok(~[String(args.head().FRtype_of().to_str())])
 }
 
-fn typeeq(_: &mut Context, args: ~[FRValue]) -> Result<~[FRValue], ~str> {
+fn typeeq(_: &mut Context, args: &[FRValue]) -> Result<~[FRValue], ~str> {
     Ok(~[Bool(args[0].FRtype_of() == args[1].FRtype_of())])
 }
 
@@ -34,10 +117,31 @@ fn test_macro(_args: ~[AST]) -> AST {
 }
 
 pub fn register_stdlib(ctx: &mut Context) {
-    ctx.global.define(~"+",      Function(~add, 2),         @Func(~[Float, Float, Float]));
+    ctx.global.define_type(~"int",    @Integer);
+    ctx.global.define_type(~"float",  @Float);
+    ctx.global.define_type(~"str",    @StringT);
+    ctx.global.define_type(~"bool",   @BoolT);
+    ctx.global.define_type(~"list",   @ListT);
+    ctx.global.define_type(~"any",    @Any);
+    let num = || OneOf(0, ~[Integer, Float]);
+    ctx.global.define(~"+",      Function(~add, 2),         @ExprT {tin: ~[num(), num()], tout: ~[num()]});
+    ctx.global.define(~"-",      Function(~sub, 2),         @ExprT {tin: ~[num(), num()], tout: ~[num()]});
+    ctx.global.define(~"*",      Function(~mul, 2),         @ExprT {tin: ~[num(), num()], tout: ~[num()]});
+    ctx.global.define(~"<",      Function(~less, 2),        @ExprT {tin: ~[num(), num()], tout: ~[BoolT]});
+    ctx.global.define(~">",      Function(~greater, 2),     @ExprT {tin: ~[num(), num()], tout: ~[BoolT]});
+    ctx.global.define(~"=",      Function(~equal, 2),       @ExprT {tin: ~[num(), num()], tout: ~[BoolT]});
+    ctx.global.define(~">int",   Function(~to_int, 1),      @Func(~[Float, Integer]));
+    ctx.global.define(~">float", Function(~to_float, 1),    @Func(~[Integer, Float]));
+    ctx.global.define(~"not",    Function(~not, 1),         @Func(~[BoolT, BoolT]));
+    ctx.global.define(~"dup",    Function(~dup, 1),         @ExprT {tin: ~[TypeVar(0)], tout: ~[TypeVar(0), TypeVar(0)]});
+    ctx.global.define(~"drop",   Function(~drop, 1),        @ExprT {tin: ~[TypeVar(0)], tout: ~[]});
+    ctx.global.define(~"swap",   Function(~swap, 2),        @ExprT {tin: ~[TypeVar(0), TypeVar(1)], tout: ~[TypeVar(1), TypeVar(0)]});
+    ctx.global.define(~"over",   Function(~over, 2),        @ExprT {tin: ~[TypeVar(0), TypeVar(1)], tout: ~[TypeVar(0), TypeVar(1), TypeVar(0)]});
+    ctx.global.define(~"call",   Word(~AST {node: Call, line: LineInfo {line: 0, startslice: 0, endslice: 0, startcol: 0, endcol: 0}, typeinfo: @Apply}), @Apply);
+    ctx.global.define(~"print",  Function(~FRprint, 1),     @ExprT {tin: ~[TypeVar(0)], tout: ~[]});
     ctx.global.define(~"list",   Function(~list, 1),        @Func(~[Any, ListT]));
     ctx.global.define(~"typeof", Function(~FRtypeof, 1),    @Func(~[Any, StringT]));
-    ctx.global.define(~"typeeq", Function(~typeeq, 2),      @Func(~[Any, Any, StringT]));
+    ctx.global.define(~"typeeq", Function(~typeeq, 2),      @Func(~[Any, Any, BoolT]));
     ctx.global.macro(~"test_macro", ~test_macro);
 }
 
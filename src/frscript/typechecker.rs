@@ -20,68 +20,266 @@ pub fn get_type(scope: &context::Scope, name: ~str) -> Result<@FRType, ~str> {
     }
 }
 
+fn var_of(T: &FRType) -> Option<uint> {
+    match *T {
+        TypeVar(n) | OneOf(n, _) => Some(n),
+        _ => None
+    }
+}
+
+// Pulls the parameters in `want` that aren't on the stack in as extra inputs
+// of the expression being inferred. Type variables among them are renamed so
+// they don't clash with the ones already standing for other inputs.
+fn grow(typestack: &mut ~[FRType], oldstack: &mut ~[FRType], want: &[FRType]) {
+    if typestack.len() < want.len() {
+        let mut next = oldstack.iter().fold(0u, |n, T| match var_of(T) {
+            Some(m) if m >= n => m + 1,
+            _ => n
+        });
+        let mut renamed = ~[];
+        let missing = want.slice_to(want.len() - typestack.len()).map(|T| match var_of(T) {
+            Some(n) => {
+                let found = renamed.iter().find(|&&(m, _)| m == n).map(|&(_, f)| f);
+                let fresh = match found {
+                    Some(f) => f,
+                    None => {
+                        renamed.push((n, next));
+                        next += 1;
+                        next - 1
+                    }
+                };
+                match *T {
+                    OneOf(_, ref alts) => OneOf(fresh, alts.clone()),
+                    _ => TypeVar(fresh)
+                }
+            }
+            None => T.clone()
+        });
+        *typestack = missing + *typestack;
+        *oldstack = missing + *oldstack;
+    }
+}
+
+// Pins down a type variable standing for an input that is still being
+// inferred, now that a word consuming it has said what it must be.
+fn narrow(vars: &mut ~[(uint, FRType)], typestack: &mut ~[FRType], oldstack: &mut ~[FRType], n: uint, T: &FRType) {
+    fn replace(U: &FRType, n: uint, T: &FRType) -> FRType {
+        match var_of(U) {
+            Some(m) if m == n => T.clone(),
+            _ => U.clone()
+        }
+    }
+    *typestack = typestack.map(|U| replace(U, n, T));
+    *oldstack = oldstack.map(|U| replace(U, n, T));
+    *vars = vars.map(|&(m, ref U)| (m, replace(U, n, T)));
+}
+
+// Matches a parameter type against the type on the stack at `ti`, binding the
+// type variables in `expected` to the types they stand for and narrowing the
+// ones on the stack.
+fn unify(vars: &mut ~[(uint, FRType)], typestack: &mut ~[FRType], oldstack: &mut ~[FRType], expected: &FRType, ti: uint) -> bool {
+    // once bound, a variable in `expected` stands for whatever the stack had
+    let bound = match var_of(expected) {
+        Some(n) => vars.iter().any(|&(m, _)| m == n),
+        None => false
+    };
+    let expected = substitute(*vars, expected);
+    let actual = typestack[ti].clone();
+    match (expected, actual.clone()) {
+        (TypeVar(n), _) => {
+            vars.push((n, actual));
+            true
+        }
+        (Any, _) => true,
+        (OneOf(n, alts), TypeVar(m)) => {
+            let T = OneOf(m, alts);
+            narrow(vars, typestack, oldstack, m, &T);
+            vars.push((n, T));
+            true
+        }
+        (OneOf(n, alts), OneOf(m, others)) => {
+            let both: ~[FRType] = alts.iter().filter(|T| others.iter().any(|U| *U == **T)).map(|T| T.clone()).collect();
+            let T = match both.len() {
+                0 => return false,
+                1 => both[0].clone(),
+                _ => OneOf(m, both)
+            };
+            narrow(vars, typestack, oldstack, m, &T);
+            vars.push((n, T));
+            true
+        }
+        // a variable of the stack's, which gets pinned down everywhere
+        // rather than shadowed by a second binding
+        (OneOf(m, alts), _) if bound => if alts.iter().any(|T| *T == actual) {
+            narrow(vars, typestack, oldstack, m, &actual);
+            true
+        } else {
+            false
+        },
+        (OneOf(n, alts), _) => if alts.iter().any(|T| *T == actual) {
+            vars.push((n, actual));
+            true
+        } else {
+            false
+        },
+        (T, TypeVar(m)) => {
+            narrow(vars, typestack, oldstack, m, &T);
+            true
+        }
+        (T, OneOf(m, others)) => if others.iter().any(|U| *U == T) {
+            narrow(vars, typestack, oldstack, m, &T);
+            true
+        } else {
+            false
+        },
+        (T, _) => T == actual
+    }
+}
+
+fn substitute(vars: &[(uint, FRType)], T: &FRType) -> FRType {
+    match var_of(T) {
+        Some(n) => match vars.iter().find(|&&(m, _)| m == n) {
+            Some(&(_, ref U)) => U.clone(),
+            None => T.clone()
+        },
+        None => T.clone()
+    }
+}
+
+fn name_of(node: &ASTNode) -> Option<~str> {
+    match *node {
+        Var(ref name) => Some(name.clone()),
+        _ => None
+    }
+}
+
+// Checks the parameters of a stack effect against the top of the stack and
+// replaces them with its results.
+fn apply(typestack: &mut ~[FRType], oldstack: &mut ~[FRType], inferring: bool, tin: &[FRType], tout: &[FRType], name: Option<~str>, exprline: LineInfo, line: LineInfo) -> Result<(), TypeError> {
+    if inferring {
+        grow(typestack, oldstack, tin);
+    }
+    if typestack.len() < tin.len() {
+        return Err(TypeError {msg: format!("{:s} requires {:u} parameters, {:u} were on stack", name.clone().unwrap_or(~"Expression"), tin.len(), typestack.len()), line: exprline})
+    }
+    let mut vars = ~[];
+    for i in range(0, tin.len()) {
+        let ti = i + typestack.len() - tin.len();
+        if !unify(&mut vars, typestack, oldstack, &tin[i], ti) {
+            return Err(TypeError {msg: format!(r"Bad argument \#{:u}{:s}: Expected {:s}, got {:s}",
+                                               i+1,
+                                               match name {
+                                                   Some(ref name) => " to " + *name,
+                                                   None => ~""
+                                               },
+                                               substitute(vars, &tin[i]).to_str(),
+                                               typestack[ti].to_str()
+                                              ), line: line})
+        }
+    }
+    let len = typestack.len();
+    typestack.truncate(len - tin.len());
+    typestack.push_all_move(tout.map(|T| substitute(vars, T)));
+    Ok(())
+}
+
+// Whether the word `name` is called anywhere within `ast`.
+fn mentions(ast: &AST, name: &str) -> bool {
+    match ast.node {
+        Var(ref s) => s.as_slice() == name,
+        Expr(ref arr) => arr.iter().any(|x| mentions(x, name)),
+        If(ref cond, ref body, ref other) => mentions(*cond, name) || mentions(*body, name) || match *other {
+            Some(ref x) => mentions(*x, name),
+            None => false
+        },
+        Literal(Quotation(ref body)) => mentions(*body, name),
+        Literal(_) | Def(_, _, _) | Call => false
+    }
+}
+
+// Code compiled once a word has been redefined, such as a quotation that's
+// only compiled when it's called, gets the new definition even if it was
+// checked against the old one, so it has to keep the same stack effect.
+fn redefinable(old: &Option<(FRValue, @FRType)>, T: @FRType, name: ~str, line: LineInfo) -> Result<(), TypeError> {
+    match *old {
+        Some((_, ref U)) if **U != *T => Err(TypeError {msg: format!("{:s} is already defined as {:s}, so it can't be redefined as {:s}", name, U.to_str(), T.to_str()), line: line}),
+        _ => Ok(())
+    }
+}
+
+// Puts back what a name meant before a definition of it was rejected.
+fn restore(scope: &mut context::Scope, name: ~str, old: Option<(FRValue, @FRType)>) {
+    match old {
+        Some((val, T)) => scope.define(name, val, T),
+        None => scope.undefine(name)
+    }
+}
+
 pub fn typecheck(scope: &mut context::Scope, token: AST, typestack: ~[FRType]) -> Result<AST, TypeError> {
+    check(scope, token, typestack, false)
+}
+
+// Typechecks the body of a word starting from an empty stack, inferring the
+// parameters it takes from the words that consume them.
+pub fn infer(scope: &mut context::Scope, token: AST) -> Result<AST, TypeError> {
+    check(scope, token, ~[], true)
+}
+
+fn check(scope: &mut context::Scope, token: AST, typestack: ~[FRType], inferring: bool) -> Result<AST, TypeError> {
     //println!("typecheck(scope, {:?}, {:?})", token, typestack);
     let build_var = |name, T| Ok(AST {node: Var(name), line: token.line, typeinfo: T});
     let build_expr: &fn(~[AST], @FRType) -> Result<AST,TypeError> = |args, T| Ok(AST {node: Expr(args), line: token.line, typeinfo: T});
     let mut typestack = typestack;
-    let oldstack = typestack.clone();
+    let mut oldstack = typestack.clone();
     match token.node.clone() {
         Expr(arr) => {
+            let mut res = ~[];
             for ast in arr.iter() {
                 //println("----------------");
                 //println!("ast {:?}", ast);
-                match typecheck(scope, ast.clone(), typestack.clone()) {
-                    Ok(AST {node: _, line: line, typeinfo: @ExprT {tin: ref tin, tout: ref tout}}) => {
+                let v = match check(scope, ast.clone(), typestack.clone(), inferring) {
+                    Ok(v) => v,
+                    Err(x) => return Err(x)
+                };
+                match v {
+                    AST {node: Literal(_), line: _, typeinfo: @ref T} => {
+                        //println!("val {:?}", T);
+                        typestack.push(T.clone())
+                    }
+                    AST {node: ref node, line: line, typeinfo: @ExprT {tin: ref tin, tout: ref tout}} => {
                         //println!("expr {:?} {:?} {:?}", tin, tout, typestack);
-                        if typestack.len() < tin.len() {
-                            return Err(TypeError {msg: format!("Expression requires {:u} parameters, {:u} were on stack", tin.len(), typestack.len()), line: token.line})
+                        match apply(&mut typestack, &mut oldstack, inferring, *tin, *tout, name_of(node), token.line, line) {
+                            Ok(()) => (),
+                            Err(e) => return Err(e)
                         }
-                        for i in range(0, tin.len()) {
-                            let ti = i + typestack.len() - tin.len();
-                            if tin[i] != typestack[ti] {
-                                return Err(TypeError {msg: format!(r"Bad argument \#{:u}: Expected {:s}, got {:s}", 
-                                                                   i+1, 
-                                                                   tin[i].to_str(), 
-                                                                   typestack[ti].to_str()
-                                                                  ), line: line})
-                            }
+                    }
+                    AST {node: _, line: line, typeinfo: @Apply} => {
+                        match typestack.pop_opt() {
+                            Some(ExprT {tin: tin, tout: tout}) => match apply(&mut typestack, &mut oldstack, inferring, tin, tout, Some(~"call"), line, line) {
+                                Ok(()) => (),
+                                Err(e) => return Err(e)
+                            },
+                            Some(T) => return Err(TypeError {msg: format!("call expects a quotation, got {:s}", T.to_str()), line: line}),
+                            None if inferring => return Err(TypeError {msg: ~"Words that call a quotation they are given need a declared stack effect", line: line}),
+                            None => return Err(TypeError {msg: ~"call expects a quotation, but the stack is empty", line: line})
                         }
-                        let len = typestack.len();
-                        typestack.truncate(len - tin.len());
-                        typestack.push_all_move(tout.clone());
                     }
-                    Ok(AST {node: ref node, line: line, typeinfo: @Func(ref arr)}) => {
+                    AST {node: ref node, line: line, typeinfo: @Func(ref arr)} => {
                         //println!("func {:?}", arr);
-                        if typestack.len() < (arr.len() - 1) {
-                            return Err(TypeError {msg: format!("Function requires {:u} parameters, {:u} were on stack", arr.len()-1, typestack.len()), line: token.line})
-                        }
-                        for i in range(0, arr.len()-1) {
-                            let ti = i + typestack.len() - (arr.len()-1);
-                            if arr[i] != typestack[ti] {
-                                return Err(TypeError {msg: format!(r"Bad argument \#{:u}{:s}: Expected {:s}, got {:s}",
-                                                                   i+1,
-                                                                   match node.clone() {
-                                                                       Var(name) => ~" to "+name,
-                                                                       _ => ~""
-                                                                   },
-                                                                   arr[i].to_str(),
-                                                                   typestack[ti].to_str()
-                                                                  ), line: line})
-                            }
+                        let nargs = arr.len() - 1;
+                        match apply(&mut typestack, &mut oldstack, inferring, arr.slice_to(nargs), arr.slice_from(nargs), name_of(node), token.line, line) {
+                            Ok(()) => (),
+                            Err(e) => return Err(e)
                         }
-                        let len = typestack.len();
-                        typestack.truncate(len - (arr.len() - 1));
-                        typestack.push(arr.last().clone());
                     }
-                    Ok(AST {node: _, line: _, typeinfo: @ref T}) => {
+                    AST {node: _, line: _, typeinfo: @ref T} => {
                         //println!("val {:?}", T);
                         typestack.push(T.clone())
                     }
-                    Err(x) => return Err(x)
                 }
+                res.push(v);
             }
-            build_expr(arr, @ExprT {tin: oldstack, tout: typestack})
+            build_expr(res, @ExprT {tin: oldstack, tout: typestack})
         },
         /*match get_type(scope, atom.clone()) {
             Ok(t) => {
@@ -117,7 +315,163 @@ pub fn typecheck(scope: &mut context::Scope, token: AST, typestack: ~[FRType]) -
             Ok(t) => build_var(s, t),
             Err(s) => Err(TypeError {msg: s, line: token.line})
         },
+        Literal(Quotation(body)) => match infer(scope, *body) {
+            Ok(v) => Ok(AST {node: Literal(Quotation(~v.clone())), line: token.line, typeinfo: v.typeinfo}),
+            Err(e) => Err(e)
+        },
         Literal(_) => Ok(token),
+        Call => Ok(AST {node: Call, line: token.line, typeinfo: @Apply}),
+        If(cond, body, other) => {
+            let cond = match check(scope, *cond, typestack.clone(), inferring) {
+                Ok(v) => v,
+                Err(e) => return Err(e)
+            };
+            match cond.typeinfo {
+                @ExprT {tin: ref tin, tout: ref tout} => match apply(&mut typestack, &mut oldstack, inferring, *tin, *tout, None, token.line, cond.line) {
+                    Ok(()) => (),
+                    Err(e) => return Err(e)
+                },
+                _ => return Err(TypeError {msg: ~"ICE: Condition isn't an expression", line: cond.line})
+            }
+            if inferring && typestack.len() < 1 {
+                grow(&mut typestack, &mut oldstack, &[BoolT]);
+            }
+            if typestack.len() > 0 {
+                let top = typestack.len() - 1;
+                unify(&mut ~[], &mut typestack, &mut oldstack, &BoolT, top);
+            }
+            match typestack.pop_opt() {
+                Some(BoolT) => (),
+                Some(T) => return Err(TypeError {msg: format!("Condition must leave a bool on the stack, got {:s}", T.to_str()), line: cond.line}),
+                None => return Err(TypeError {msg: ~"Condition must leave a bool on the stack, got nothing", line: cond.line})
+            }
+            let body = match check(scope, *body, typestack.clone(), inferring) {
+                Ok(v) => v,
+                Err(e) => return Err(e)
+            };
+            let mut bodyout = match body.typeinfo {
+                @ExprT {tin: ref tin, tout: ref tout} => {
+                    if inferring {
+                        grow(&mut typestack, &mut oldstack, *tin);
+                    }
+                    tout.clone()
+                }
+                _ => return Err(TypeError {msg: ~"ICE: Body of if isn't an expression", line: body.line})
+            };
+            let (other, otherout) = match other {
+                Some(e) => match check(scope, *e, typestack.clone(), inferring) {
+                    Ok(v) => match v.typeinfo {
+                        @ExprT {tin: ref tin, tout: ref tout} => {
+                            // parameters only the else branch uses pass under the if branch untouched
+                            if inferring && tin.len() > typestack.len() {
+                                let missing = tin.slice_to(tin.len() - typestack.len()).to_owned();
+                                bodyout = missing + bodyout;
+                                grow(&mut typestack, &mut oldstack, *tin);
+                            }
+                            (Some(~v.clone()), tout.clone())
+                        }
+                        _ => return Err(TypeError {msg: ~"ICE: Body of else isn't an expression", line: v.line})
+                    },
+                    Err(e) => return Err(e)
+                },
+                None => (None, typestack.clone())
+            };
+            if bodyout.len() != otherout.len() || bodyout.iter().zip(otherout.iter()).any(|(u, v)| u != v) {
+                return Err(TypeError {msg: format!("Branches of if leave different stacks: {:s} and {:s}", bodyout.to_str(), otherout.to_str()), line: token.line})
+            }
+            Ok(AST {node: If(~cond, ~body, other), line: token.line, typeinfo: @ExprT {tin: oldstack, tout: bodyout}})
+        },
+        Def(name, Some(T), body) => {
+            let (tin, tout) = match T {
+                @ExprT {tin: ref tin, tout: ref tout} => (tin.clone(), tout.clone()),
+                _ => return Err(TypeError {msg: ~"ICE: Stack effect isn't an expression type", line: token.line})
+            };
+            let old = scope.lookup(name.clone());
+            match redefinable(&old, T, name.clone(), token.line) {
+                Ok(()) => (),
+                Err(e) => return Err(e)
+            }
+            // defined before the body is checked so that the body can call
+            // it, and put back the way it was if the body is rejected
+            scope.define(name.clone(), Word(body.clone()), T);
+            let v = match check(scope, *body, tin, false) {
+                Ok(v) => v,
+                Err(e) => {
+                    restore(scope, name, old);
+                    return Err(e)
+                }
+            };
+            match v.typeinfo {
+                @ExprT {tin: _, tout: ref out} => if out.len() != tout.len() || tout.iter().zip(out.iter()).any(|(u, v)| u != v) {
+                    restore(scope, name.clone(), old);
+                    return Err(TypeError {msg: format!("Body of {:s} leaves {:s} on the stack, but its stack effect says {:s}", name, out.to_str(), tout.to_str()), line: token.line})
+                },
+                _ => {
+                    restore(scope, name, old);
+                    return Err(TypeError {msg: ~"ICE: Body of definition isn't an expression", line: v.line})
+                }
+            }
+            scope.define(name.clone(), Word(~v.clone()), T);
+            Ok(AST {node: Def(name, Some(T), ~v), line: token.line, typeinfo: T})
+        },
+        Def(name, None, body) => {
+            if mentions(body, name) {
+                return Err(TypeError {msg: format!("{:s} calls itself, so it needs a declared stack effect", name), line: token.line})
+            }
+            match infer(scope, *body) {
+                Ok(v) => {
+                    match redefinable(&scope.lookup(name.clone()), v.typeinfo, name.clone(), token.line) {
+                        Ok(()) => (),
+                        Err(e) => return Err(e)
+                    }
+                    scope.define(name.clone(), Word(~v.clone()), v.typeinfo);
+                    Ok(AST {node: Def(name, None, ~v.clone()), line: token.line, typeinfo: v.typeinfo})
+                }
+                Err(e) => Err(e)
+            }
+        },
     }
 }
 
+#[cfg(test)]
+mod test {
+    use parse::*;
+    use grammar::*;
+    use ast::*;
+    use context::*;
+    use macro::*;
+    use stdlib::*;
+    use super::*;
+
+    // Typechecks each line in turn against the same scope, as the REPL
+    // would, starting each one with an empty stack. Says which ones passed.
+    fn check_lines(lines: &[&str]) -> ~[bool] {
+        let grammar = grammar();
+        let mut state = Context::new();
+        register_stdlib(&mut state);
+        let mut passed = ~[];
+        for line in lines.iter() {
+            let res = parse_at(&grammar, grammar.grammar.get(& &"repl-stat"), *line, 0)  .map_err(|e| e.to_str())
+                     .and_then(|tree| build_ast(&mut state.global, tree)           .map_err(|e| e.msg.clone()))
+                     .and_then(|ast|  expand_macros(&mut state, ast)               .map_err(|e| e.to_str()))
+                     .and_then(|ast|  typecheck(&mut state.global, ast, ~[])       .map_err(|e| e.msg.clone()));
+            passed.push(res.is_ok());
+        }
+        passed
+    }
+
+    #[test]
+    fn redefinition_must_keep_the_stack_effect() {
+        assert_eq!(check_lines(["def f: 1", "def f: 2", "def g: f 1 +", "def f: \"s\""]), ~[true, true, true, false]);
+    }
+
+    #[test]
+    fn inferred_arithmetic_keeps_its_operand_type() {
+        assert_eq!(check_lines(["def inc: 1 +", "2 inc", "2.5 inc"]), ~[true, true, false]);
+    }
+
+    #[test]
+    fn rejected_definition_is_not_left_in_scope() {
+        assert_eq!(check_lines(["def g (bool -- bool): drop drop drop true", "false g"]), ~[false, false]);
+    }
+}
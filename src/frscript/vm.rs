@@ -0,0 +1,181 @@
+use parse::*;
+use context::*;
+use ast::*;
+use compiler::*;
+
+pub struct EvalError {
+    msg: ~str,
+    line: LineInfo
+}
+
+impl ToStr for EvalError {
+    fn to_str(&self) -> ~str {
+        self.line.to_str() + ": " + self.msg.clone()
+    }
+}
+
+pub fn run(ctx: &mut Context, prog: &mut Program, entry: uint, stack: ~[FRValue]) -> Result<~[FRValue], EvalError> {
+    let mut stack = stack;
+    let mut calls = ~[];
+    let mut pc = entry;
+    loop {
+        let instr = prog.code[pc];
+        match instr {
+            Push(idx) => stack.push(prog.consts[idx].clone()),
+            CallHost(slot) => {
+                let (f, nargs) = prog.hosts[slot];
+                let len = stack.len();
+                // the arguments are lent straight off the stack
+                let res = f(ctx, stack.slice_from(len - nargs));
+                match res {
+                    Ok(v) => {
+                        stack.truncate(len - nargs);
+                        stack.push_all_move(v)
+                    }
+                    Err(e) => return Err(EvalError {msg: e, line: prog.lines[pc]})
+                }
+            }
+            CallWord(slot) => {
+                calls.push(pc + 1);
+                pc = prog.words[slot];
+                continue
+            }
+            CallQuote => match stack.pop() {
+                Compiled(addr, _) => {
+                    calls.push(pc + 1);
+                    pc = addr;
+                    continue
+                }
+                Quotation(body) => match compile(ctx, prog, body) {
+                    Ok(addr) => {
+                        calls.push(pc + 1);
+                        pc = addr;
+                        continue
+                    }
+                    Err(e) => return Err(EvalError {msg: e.msg, line: e.line})
+                },
+                _ => return Err(EvalError {msg: ~"ICE: Type checker didn't catch call without a quotation", line: prog.lines[pc]})
+            },
+            Jump(addr) => {
+                pc = addr;
+                continue
+            }
+            JumpUnless(addr) => match stack.pop() {
+                Bool(true) => (),
+                Bool(false) => {
+                    pc = addr;
+                    continue
+                }
+                _ => return Err(EvalError {msg: ~"ICE: Type checker didn't catch non-boolean condition", line: prog.lines[pc]})
+            },
+            Return => match calls.pop_opt() {
+                Some(addr) => {
+                    pc = addr;
+                    continue
+                }
+                None => return Ok(stack)
+            }
+        }
+        pc += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use parse::*;
+    use grammar::*;
+    use ast::*;
+    use context::*;
+    use macro::*;
+    use stdlib::*;
+    use typechecker::*;
+    use compiler::*;
+    use super::*;
+
+    // Runs each line in turn as the REPL does, carrying the stack and its
+    // types over from one to the next. Gives the stack after the last line,
+    // or the first error.
+    fn run_lines(prog: &mut Program, lines: &[&str]) -> Result<~[~str], ~str> {
+        let grammar = grammar();
+        let mut state = Context::new();
+        register_stdlib(&mut state);
+        let mut values = ~[];
+        let mut types = ~[];
+        for line in lines.iter() {
+            let mark = prog.mark();
+            let ast = parse_at(&grammar, grammar.grammar.get(& &"repl-stat"), *line, 0) .map_err(|e| e.to_str())
+                     .and_then(|tree| build_ast(&mut state.global, tree)          .map_err(|e| e.msg.clone()))
+                     .and_then(|ast|  expand_macros(&mut state, ast)              .map_err(|e| e.to_str()))
+                     .and_then(|ast|  typecheck(&mut state.global, ast, types.clone()).map_err(|e| e.msg.clone()));
+            let ast = match ast {
+                Ok(ast) => ast,
+                Err(e) => return Err(e)
+            };
+            let entry = match compile(&state, prog, &ast) {
+                Ok(entry) => entry,
+                Err(e) => return Err(e.msg)
+            };
+            values = match run(&mut state, prog, entry, values.clone()) {
+                Ok(v) => v,
+                Err(e) => return Err(e.msg)
+            };
+            match ast {
+                AST {node: Def(_, _, _), line: _, typeinfo: _} => (),
+                AST {node: _, line: _, typeinfo: @ExprT {tin: _, tout: ref tout}} => types = tout.clone(),
+                _ => ()
+            }
+            prog.release(mark, values);
+        }
+        Ok(values.map(|v| v.to_str()))
+    }
+
+    fn run_fresh(lines: &[&str]) -> Result<~[~str], ~str> {
+        run_lines(&mut Program::new(), lines)
+    }
+
+    #[test]
+    fn stack_carries_over_between_lines() {
+        assert_eq!(run_fresh(["1 2 +", "3 *"]), Ok(~[~"9"]));
+    }
+
+    #[test]
+    fn if_runs_one_branch() {
+        assert_eq!(run_fresh(["if 1 2 <: 10 else: 20"]), Ok(~[~"10"]));
+        assert_eq!(run_fresh(["if 2 1 <: 10 else: 20"]), Ok(~[~"20"]));
+    }
+
+    #[test]
+    fn quotations_can_be_called() {
+        assert_eq!(run_fresh(["2 [ 1 + ] call"]), Ok(~[~"3"]));
+    }
+
+    #[test]
+    fn words_can_recurse() {
+        assert_eq!(run_fresh(["def down (int -- int): if dup 0 >: 1 - down else: drop 7", "5 down"]), Ok(~[~"7"]));
+    }
+
+    #[test]
+    fn redefinition_leaves_compiled_callers_alone() {
+        assert_eq!(run_fresh(["def f: 1", "def g: f", "def f: 2", "g f"]), Ok(~[~"1", ~"2"]));
+    }
+
+    #[test]
+    fn statements_are_dropped_once_run() {
+        let mut prog = Program::new();
+        assert!(run_lines(&mut prog, ["1 2 +"]).is_ok());
+        let size = prog.code.len();
+        assert!(run_lines(&mut prog, ["3 4 +", "5 6 * drop"]).is_ok());
+        assert_eq!(prog.code.len(), size);
+        // a quotation left on the stack keeps its code
+        assert!(run_lines(&mut prog, ["[ 1 ]"]).is_ok());
+        assert!(prog.code.len() > size);
+    }
+
+    #[test]
+    fn integer_overflow_is_an_error() {
+        assert_eq!(run_fresh(["9223372036854775807 1 +"]), Err(~"9223372036854775807 and 1 overflow an int"));
+        assert!(run_fresh(["-9223372036854775807 2 -"]).is_err());
+        assert!(run_fresh(["4294967296 4294967296 *"]).is_err());
+        assert!(run_fresh(["1e30 >int"]).is_err());
+    }
+}
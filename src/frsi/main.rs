@@ -3,9 +3,11 @@
 extern mod frscript; 
 
 use std::io::*;
+use std::os;
 use frscript::parse::*;
 use frscript::grammar::*;
-use frscript::eval::*;
+use frscript::compiler::*;
+use frscript::vm::*;
 use frscript::context::*;
 use frscript::stdlib::*;
 use frscript::typechecker::*;
@@ -14,34 +16,190 @@ use frscript::macro::*;
 
 fn pretty_error(line: LineInfo, err: ~str) -> ~str {
     let mut s = ~"";
-    for _ in range(0, line.startslice+2) {
+    for _ in range(0, line.startcol+2) {
         s = s + " "
     }
-    for _ in range(line.startslice, line.endslice) {
+    for _ in range(line.startcol, line.endcol) {
         s = s + "^";
     }
     s + "\n" + err
 }
 
-fn main() {
-    let grammar = grammar();
+// Reports an error in a script as `path:line:col: msg`, followed by the
+// offending line with the span underlined.
+fn file_error(path: &str, source: &str, line: LineInfo, msg: ~str) {
+    // columns count characters, so the line is found from the byte offset
+    let start = match source.slice_to(std::uint::min(line.startslice, source.len())).rfind('\n') {
+        Some(n) => n + 1,
+        None => 0
+    };
+    let end = match source.slice_from(start).find('\n') {
+        Some(n) => start + n,
+        None => source.len()
+    };
+    let text = source.slice(start, end);
+    let mut s = format!("{:s}:{:i}:{:u}: {:s}\n{:s}\n", path, line.line + 1, line.startcol + 1, msg, text);
+    for _ in range(0, line.startcol) {
+        s = s + " "
+    }
+    // spans running onto later lines are only underlined up to the end of
+    // this one
+    for _ in range(line.startcol, std::uint::max(line.startcol + 1, std::uint::min(line.endcol, text.char_len()))) {
+        s = s + "^";
+    }
+    stderr().write_line(s);
+}
+
+// Runs a script, returning whether it ran without errors. The whole file is
+// parsed before anything is run, so a syntax error anywhere means nothing
+// runs, and every syntax error in the file is reported.
+fn run_file(path: &str) -> bool {
+    let source = match read_whole_file_str(&Path(path)) {
+        Ok(s) => s,
+        Err(e) => {
+            stderr().write_line(format!("frsi: {:s}", e));
+            return false
+        }
+    };
+    let mut grammar = grammar();
+    grammar.memoize();
+    let (tree, errors) = parse_all(&grammar, grammar.grammar.get(& &"file"), source);
+    if errors.len() > 0 {
+        for e in errors.iter() {
+            file_error(path, source, e.line, e.message());
+        }
+        return false
+    }
+    let stats = match tree {
+        Some(Token {value: FRSeq(arr), line: _}) => arr,
+        _ => ~[]
+    };
+    let mut state = Context::new();
+    let mut program = Program::new();
+    register_stdlib(&mut state);
+    for tree in stats.move_iter() {
+        match run_statement(&mut state, &mut program, tree) {
+            Ok(()) => (),
+            Err((line, msg)) => {
+                file_error(path, source, line, msg);
+                return false
+            }
+        }
+    }
+    true
+}
+
+// Runs a script from stdin one statement at a time, each as soon as it has
+// been read, so it can be piped in from something still writing it. Stops at
+// the first error of any kind.
+fn run_stdin() -> bool {
+    let mut grammar = grammar();
+    grammar.memoize();
+    let stream = Stream::new(&grammar, grammar.grammar.get(& &"statement"), grammar.grammar.get(& &"ws"), grammar.grammar.get(& &"resync"), stdin());
+    let mut state = Context::new();
+    let mut program = Program::new();
+    register_stdlib(&mut state);
+    for res in stream {
+        let res = match res {
+            Ok(tree) => run_statement(&mut state, &mut program, tree),
+            Err(e) => Err((e.line, e.message()))
+        };
+        match res {
+            Ok(()) => (),
+            Err((line, msg)) => {
+                // the source isn't kept, so there's no line to show
+                stderr().write_line(format!("<stdin>:{:i}:{:u}: {:s}", line.line + 1, line.startcol + 1, msg));
+                return false
+            }
+        }
+    }
+    true
+}
+
+// Builds, checks, compiles and runs one toplevel statement of a script.
+// Unlike lines in the REPL, each one starts on an empty stack.
+fn run_statement(state: &mut Context, program: &mut Program, tree: Token<FRToken>) -> Result<(), (LineInfo, ~str)> {
+    let mark = program.mark();
+    let res = build_ast(&mut state.global, tree)                    .map_err(|e| (e.line, e.msg.clone()))
+             .and_then(|ast|   expand_macros(&mut *state, ast.clone())          .map_err(|e| (e.line, e.to_str())))
+             .and_then(|ast|   typecheck(&mut state.global, ast.clone(), ~[])   .map_err(|e| (e.line, e.msg.clone())))
+             .and_then(|ast|   compile(&*state, &mut *program, &ast)            .map_err(|e| (e.line, e.msg.clone())))
+             .and_then(|entry| run(&mut *state, &mut *program, entry, ~[])      .map_err(|e| (e.line, e.msg.clone())));
+    program.release(mark, []);
+    res.map(|_| ())
+}
+
+// The types on the stack once a toplevel statement has run. Definitions
+// leave it alone.
+fn stack_after(ast: &AST, types: &[FRType]) -> ~[FRType] {
+    match *ast {
+        AST {node: Def(_, _, _), line: _, typeinfo: _} => types.to_owned(),
+        AST {node: _, line: _, typeinfo: @ExprT {tin: _, tout: ref tout}} => tout.clone(),
+        _ => types.to_owned()
+    }
+}
+
+fn show_stack(values: &[FRValue], types: &[FRType]) -> ~str {
+    let items: ~[~str] = values.iter().zip(types.iter()).map(|(v, T)| format!("{:s} : {:s}", v.to_str(), T.to_str())).collect();
+    "[" + items.connect(", ") + "]"
+}
+
+fn repl() {
+    let mut grammar = grammar();
+    grammar.memoize();
     let mut state = Context::new();
+    let mut program = Program::new();
     register_stdlib(&mut state);
+    // the stack carries over from one line to the next, along with its types
+    let mut values = ~[];
+    let mut types = ~[];
     loop {
         print("= ");
         let line = stdin().read_line();
         if line == ~"quit" || line == ~"exit" {
             return
         }
-        let res = parse(&grammar, grammar.grammar.get(& &"repl-stat"), line, 0)     .map_err(|e| pretty_error(e.line, e.to_str()))
+        grammar.forget();
+        let (tree, errors) = parse_all(&grammar, grammar.grammar.get(& &"repl-stat"), line);
+        let parsed = if errors.len() > 0 {
+            Err(pretty_error(errors[0].line, errors[0].to_str()))
+        } else {
+            Ok(tree.unwrap())
+        };
+        let mark = program.mark();
+        let res = parsed
                  .and_then(|tree|  build_ast(&mut state.global, tree.clone())       .map_err(|e| pretty_error(e.line, e.to_str())))
                  .and_then(|ast|   expand_macros(&mut state, ast.clone())           .map_err(|e| pretty_error(e.line, e.to_str())))
-                 .and_then(|ast|   typecheck(&mut state.global, ast.clone(), ~[])   .map_err(|e| pretty_error(e.line, e.to_str())))
-                 .and_then(|ast|   eval(&mut state, ast, ~[])                       .map_err(|e| pretty_error(e.line, e.to_str())));
-        println(match res {
-            Ok(v) => v.to_str(),
-            Err(e) => e.to_str()
-        })
+                 .and_then(|ast|   typecheck(&mut state.global, ast.clone(), types.clone()).map_err(|e| pretty_error(e.line, e.to_str())))
+                 .and_then(|ast|   compile(&state, &mut program, &ast)              .map_err(|e| pretty_error(e.line, e.to_str()))
+                                                                                    .map(|entry| (entry, stack_after(&ast, types))))
+                 .and_then(|(entry, tout)| run(&mut state, &mut program, entry, values.clone()).map_err(|e| pretty_error(e.line, e.to_str()))
+                                                                                    .map(|v| (v, tout)));
+        match res {
+            Ok((v, tout)) => {
+                values = v;
+                types = tout;
+                println(show_stack(values, types))
+            }
+            Err(e) => println(e)
+        }
+        program.release(mark, values);
     }
 }
 
+fn main() {
+    let args = os::args();
+    match args.len() {
+        1 => repl(),
+        2 if args[1] == ~"-" => if !run_stdin() {
+            os::set_exit_status(1)
+        },
+        2 => if !run_file(args[1]) {
+            os::set_exit_status(1)
+        },
+        _ => {
+            stderr().write_line("usage: frsi [script.frs | -]");
+            os::set_exit_status(2)
+        }
+    }
+}